@@ -0,0 +1,564 @@
+use std::fmt;
+use std::ops::Range;
+
+use crate::reducer::{ActionContext, ReducerRegistry};
+use crate::state_root::{self, BlockState};
+use crate::store::{Backends, StoreGeneric};
+use crate::types::{
+    actions::{Action, ApplyStatus},
+    block::{Block, BlockHeader, BlockPolicy, ChainTip, EventRange},
+    events::EventRecord,
+    layer::LayerTag,
+    value::{actions_root, Hash},
+};
+
+pub struct BlockOutcome {
+    /// One status per submitted action.
+    pub statuses: Vec<ApplyStatus>,
+    /// Height the block was recorded at, if anything was recorded.
+    pub height: Option<u64>,
+}
+
+/// Applies `actions` as the next block under the chain's `BlockPolicy`. `ctx` carries the
+/// block's caller and time, which are recorded with it.
+///
+/// A recorded block is left staged in its own tagged layer on top of the store, for the
+/// caller to commit or keep speculative. If nothing is recorded, nothing stays staged.
+pub fn apply_block<S: Backends>(
+    reducers: &ReducerRegistry<S>,
+    store: &mut StoreGeneric<S>,
+    ctx: ActionContext,
+    actions: Vec<Action>,
+) -> BlockOutcome {
+    let policy = store
+        .meta
+        .get()
+        .and_then(|m| m.block_policy)
+        .unwrap_or_default();
+    let height = store.blocks.len() as u64;
+    let events_start = store.events.len() as u64;
+    store.push_tagged_layer(LayerTag::at_height(height));
+
+    let mut statuses = Vec::with_capacity(actions.len());
+    let mut events_per_action = Vec::with_capacity(actions.len());
+    let mut first_err: Option<usize> = None;
+    for (i, a) in actions.iter().enumerate() {
+        if first_err.is_some() && policy == BlockPolicy::StopAtFirstError {
+            statuses.push(ApplyStatus::Pass {
+                reason: "not applied: block stopped at an earlier error".into(),
+            });
+            events_per_action.push(0);
+            continue;
+        }
+        let before = store.events.len();
+        let status = reducers.reduce_in_order(store, &ctx, a);
+        if let ApplyStatus::Err { .. } = status {
+            first_err.get_or_insert(i);
+        }
+        statuses.push(status);
+        events_per_action.push((store.events.len() - before) as u32);
+    }
+
+    // Only what was actually applied goes into the recorded block.
+    let applied = match (policy, first_err) {
+        (_, None) | (BlockPolicy::SkipFailed, Some(_)) => actions.len(),
+        (BlockPolicy::Atomic, Some(_)) => 0,
+        (BlockPolicy::StopAtFirstError, Some(i)) => i,
+    };
+    if applied == 0 && !actions.is_empty() {
+        store.revert_top();
+        return BlockOutcome {
+            statuses,
+            height: None,
+        };
+    }
+    let actions = actions[..applied].to_vec();
+    let state = state_root::after_block(store);
+    let header = BlockHeader {
+        height,
+        parent_hash: tip_hash(store).map(|h| h.to_vec()),
+        timestamp_ns: ctx.timestamp_ns,
+        actions_root: actions_root(&actions).to_vec(),
+        state_root: state.root.to_vec(),
+        events_range: EventRange {
+            start: events_start,
+            end: store.events.len() as u64,
+        },
+        events_per_action: events_per_action[..applied].to_vec(),
+    };
+    let blk = Block {
+        header: Some(header),
+        actions,
+        results: statuses[..applied].to_vec(),
+        caller: ctx.caller,
+    };
+    append_block(store, &blk, &state);
+    BlockOutcome {
+        statuses,
+        height: Some(height),
+    }
+}
+
+/// The newest recorded block.
+pub fn tip_block<S: Backends>(store: &StoreGeneric<S>) -> Option<Block> {
+    let bytes = store.blocks.last()?;
+    Some(candid::decode_one(&bytes).expect("decode block"))
+}
+
+/// Hash of the newest recorded block, which the next block chains to. Read from the
+/// stored tip; logs written before the tip was stored fall back to hashing the last block.
+pub fn tip_hash<S: Backends>(store: &StoreGeneric<S>) -> Option<Hash> {
+    let len = store.blocks.len() as u64;
+    if len == 0 {
+        return None;
+    }
+    let stored = store
+        .tip
+        .get()
+        .filter(|t| t.height + 1 == len)
+        .and_then(|t| Hash::try_from(t.hash.as_slice()).ok());
+    stored.or_else(|| tip_block(store).map(|b| b.hash()))
+}
+
+/// Appends `block` to the log and makes it the tip; `state` is the state after it.
+pub fn append_block<S: Backends>(store: &mut StoreGeneric<S>, block: &Block, state: &BlockState) {
+    let height = store.blocks.len() as u64;
+    store
+        .blocks
+        .append(candid::encode_one(block).expect("encode block"));
+    store.tip.set(ChainTip {
+        height,
+        hash: block.hash().to_vec(),
+        balances: state.balances.to_vec(),
+    });
+}
+
+/// Re-applies a recorded block on top of the current state, skipping actions whose
+/// recorded result is `Err` (they were reverted when the block was produced).
+/// The block itself is not appended. Returns how many events each action emitted, or the
+/// first action that does not end the way the block records.
+///
+/// Blocks recorded before actions were bound to a caller carry none and were applied
+/// without authorization checks; their actions are replayed as already authorized.
+pub fn replay_block<S: Backends>(
+    reducers: &ReducerRegistry<S>,
+    store: &mut StoreGeneric<S>,
+    block: &Block,
+) -> Result<Vec<u32>, ReplayError> {
+    let mut ctx = ActionContext::for_block(block.caller.clone(), block.timestamp_ns());
+    ctx.preauthorized = block.caller.is_none();
+    let mut events_per_action = Vec::with_capacity(block.actions.len());
+    for (i, (action, result)) in block.actions.iter().zip(block.results.iter()).enumerate() {
+        let before = store.events.len();
+        if !matches!(result, ApplyStatus::Err { .. }) {
+            let replayed = reducers.reduce_in_order(store, &ctx, action);
+            if status_kind(&replayed) != status_kind(result) {
+                return Err(ReplayError {
+                    action: i,
+                    recorded: result.clone(),
+                    replayed,
+                });
+            }
+        }
+        events_per_action.push((store.events.len() - before) as u32);
+    }
+    Ok(events_per_action)
+}
+
+// Pass reasons may be worded differently than when the block was produced; only the
+// kind of result has to match.
+fn status_kind(status: &ApplyStatus) -> &'static str {
+    match status {
+        ApplyStatus::Ok => "ok",
+        ApplyStatus::Pass { .. } => "pass",
+        ApplyStatus::Err { .. } => "err",
+    }
+}
+
+/// The events block `height` emitted, each with the action that emitted it. `None` if
+/// there is no such block, or it was recorded before headers existed.
+pub fn block_events<S: Backends>(store: &StoreGeneric<S>, height: u64) -> Option<Vec<EventRecord>> {
+    let bytes = store.blocks.get(height as usize)?;
+    let block: Block = candid::decode_one(&bytes).expect("decode block");
+    let header = block.header?;
+    let range = header.events_range;
+    let action_indices = header
+        .events_per_action
+        .iter()
+        .enumerate()
+        .flat_map(|(i, n)| std::iter::repeat_n(i as u32, *n as usize));
+    let events = store.events.range(range.start as usize..range.end as usize);
+    Some(
+        events
+            .zip(action_indices)
+            .map(|(event, action_index)| EventRecord {
+                block_height: height,
+                action_index,
+                event,
+            })
+            .collect(),
+    )
+}
+
+/// A replayed action that ended differently than the block records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayError {
+    pub action: usize,
+    pub recorded: ApplyStatus,
+    pub replayed: ApplyStatus,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "action {} was recorded as {} but replayed as {}",
+            self.action,
+            status_kind(&self.recorded),
+            status_kind(&self.replayed)
+        )?;
+        match &self.replayed {
+            ApplyStatus::Ok => Ok(()),
+            ApplyStatus::Pass { reason } => write!(f, " ({reason})"),
+            ApplyStatus::Err { error } => write!(f, " ({error})"),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+
+/// Why a block's header does not fit onto the local chain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    /// A block without a header followed one that had a header.
+    Missing {
+        height: u64,
+    },
+    Height {
+        expected: u64,
+        got: u64,
+    },
+    ParentHash {
+        height: u64,
+    },
+    ActionsRoot {
+        height: u64,
+    },
+    TimestampRegressed {
+        height: u64,
+        parent: u64,
+        got: u64,
+    },
+    EventsRange {
+        height: u64,
+        expected: EventRange,
+        got: EventRange,
+    },
+    Replay {
+        height: u64,
+        error: ReplayError,
+    },
+    /// Replay produced different balances or `Meta` than the block's producer.
+    StateRoot {
+        height: u64,
+    },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::Missing { height } => write!(f, "block {height} has no header"),
+            HeaderError::Height { expected, got } => {
+                write!(f, "expected block {expected}, got a header for block {got}")
+            }
+            HeaderError::ParentHash { height } => {
+                write!(f, "block {height} does not chain to the local tip")
+            }
+            HeaderError::ActionsRoot { height } => {
+                write!(f, "block {height} actions do not match its actions root")
+            }
+            HeaderError::TimestampRegressed {
+                height,
+                parent,
+                got,
+            } => write!(
+                f,
+                "block {height} timestamp {got} is before its parent's {parent}"
+            ),
+            HeaderError::EventsRange {
+                height,
+                expected,
+                got,
+            } => write!(
+                f,
+                "block {height} emitted events {}..{}, split differently or not matching {}..{}",
+                expected.start, expected.end, got.start, got.end
+            ),
+            HeaderError::Replay { height, error } => write!(f, "block {height}: {error}"),
+            HeaderError::StateRoot { height } => {
+                write!(
+                    f,
+                    "state after block {height} does not match its state root"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+/// `replay_block` for a block received from elsewhere: checks that its header follows the
+/// local tip before replaying, and that replay emitted the events and reached the state
+/// the header claims. Returns that state, for `append_block`.
+///
+/// The block must be replayed into a layer of its own, pushed by the caller; on error that
+/// layer may hold a partial replay and is the caller's to revert. Blocks recorded before
+/// headers existed are replayed unchecked, but only while the local tip has no header
+/// either.
+pub fn replay_verified_block<S: Backends>(
+    reducers: &ReducerRegistry<S>,
+    store: &mut StoreGeneric<S>,
+    block: &Block,
+) -> Result<BlockState, HeaderError> {
+    let height = store.blocks.len() as u64;
+    let tip = tip_block(store);
+    let Some(header) = &block.header else {
+        if tip.as_ref().is_some_and(|t| t.header.is_some()) {
+            return Err(HeaderError::Missing { height });
+        }
+        replay_block(reducers, store, block)
+            .map_err(|error| HeaderError::Replay { height, error })?;
+        return Ok(state_root::after_block(store));
+    };
+    if header.height != height {
+        return Err(HeaderError::Height {
+            expected: height,
+            got: header.height,
+        });
+    }
+    let parent_hash = tip_hash(store).map(|h| h.to_vec());
+    if header.parent_hash != parent_hash {
+        return Err(HeaderError::ParentHash { height });
+    }
+    if header.actions_root != actions_root(&block.actions) {
+        return Err(HeaderError::ActionsRoot { height });
+    }
+    if let (Some(parent), Some(got)) = (tip.and_then(|t| t.timestamp_ns()), header.timestamp_ns) {
+        if got < parent {
+            return Err(HeaderError::TimestampRegressed {
+                height,
+                parent,
+                got,
+            });
+        }
+    }
+    let start = store.events.len() as u64;
+    let events_per_action = replay_block(reducers, store, block)
+        .map_err(|error| HeaderError::Replay { height, error })?;
+    let emitted = EventRange {
+        start,
+        end: store.events.len() as u64,
+    };
+    if emitted != header.events_range || header.events_per_action != events_per_action {
+        return Err(HeaderError::EventsRange {
+            height,
+            expected: emitted,
+            got: header.events_range,
+        });
+    }
+    let state = state_root::after_block(store);
+    if header.state_root != state.root {
+        return Err(HeaderError::StateRoot { height });
+    }
+    Ok(state)
+}
+
+/// Where `verify_chain` found the block log broken.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChainError {
+    Undecodable {
+        height: u64,
+    },
+    /// The block's parent hash is not the hash of the block before it.
+    BrokenLink {
+        height: u64,
+    },
+    /// The block's header names a different height than its position in the log.
+    Misplaced {
+        height: u64,
+        claimed: u64,
+    },
+    /// The stored tip disagrees with the last block.
+    TipMismatch {
+        height: u64,
+    },
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::Undecodable { height } => write!(f, "block {height} does not decode"),
+            ChainError::BrokenLink { height } => match height.checked_sub(1) {
+                Some(parent) => write!(f, "block {height} does not chain to block {parent}"),
+                None => write!(f, "block {height} names a parent but is the first block"),
+            },
+            ChainError::Misplaced { height, claimed } => {
+                write!(f, "block at {height} claims height {claimed}")
+            }
+            ChainError::TipMismatch { height } => {
+                write!(f, "stored tip does not match block {height}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+/// Checks that blocks `range` of the log form an unbroken hash chain: every block names
+/// the hash of the one before it as its parent and sits at the height its header claims.
+/// A range reaching the end of the log is also checked against the stored tip.
+///
+/// Blocks recorded before blocks were hash-chained carry no parent hash; they are only
+/// accepted before the first block that does.
+pub fn verify_chain<S: Backends>(
+    store: &StoreGeneric<S>,
+    range: Range<u64>,
+) -> Result<(), ChainError> {
+    let len = store.blocks.len() as u64;
+    let Range { start, end } = start_end(range, len);
+    let decode = |height: u64, bytes: Vec<u8>| {
+        candid::decode_one::<Block>(&bytes).map_err(|_| ChainError::Undecodable { height })
+    };
+    let mut prev: Option<Block> = match start.checked_sub(1) {
+        Some(h) => Some(decode(
+            h,
+            store.blocks.get(h as usize).expect("block in range"),
+        )?),
+        None => None,
+    };
+    for (height, bytes) in (start..end).zip(store.blocks.range(start as usize..end as usize)) {
+        let block = decode(height, bytes)?;
+        if let Some(claimed) = block.height().filter(|h| *h != height) {
+            return Err(ChainError::Misplaced { height, claimed });
+        }
+        let linked = match (&prev, block.parent_hash()) {
+            (None, parent) => parent.is_none(),
+            (Some(p), Some(parent)) => parent == p.hash(),
+            // Only a block in the unchained prefix may omit its parent hash.
+            (Some(p), None) => block.header.is_none() && p.header.is_none(),
+        };
+        if !linked {
+            return Err(ChainError::BrokenLink { height });
+        }
+        prev = Some(block);
+    }
+    if end == len {
+        if let (Some(last), Some(tip)) = (&prev, store.tip.get()) {
+            // A tip without a usable hash is passed over, as in `tip_hash`.
+            let stored = Hash::try_from(tip.hash.as_slice()).ok();
+            if tip.height + 1 == len && stored.is_some_and(|h| h != last.hash()) {
+                return Err(ChainError::TipMismatch { height: tip.height });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn start_end(range: Range<u64>, len: u64) -> Range<u64> {
+    let end = range.end.min(len);
+    range.start.min(end)..end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::mem::{self, addr};
+    use crate::types::{actions::LedgerAction, address::Address, meta::Meta};
+
+    fn mint_block(caller: Option<Address>) -> Block {
+        Block {
+            header: None,
+            actions: vec![Action::Ledger(LedgerAction::Coinbase {
+                to: addr(1),
+                amount: 50,
+                token: None,
+            })],
+            results: vec![ApplyStatus::Ok],
+            caller,
+        }
+    }
+
+    fn owned_store() -> mem::MemStore {
+        let mut store = mem::store(usize::MAX);
+        store.meta.set(Meta {
+            owner: Some(addr(7)),
+            ..Meta::default()
+        });
+        store
+    }
+
+    #[test]
+    fn legacy_block_replays_without_a_caller() {
+        let reducers = ReducerRegistry::with_defaults(b"test".to_vec());
+        let mut store = owned_store();
+        assert_eq!(replay_block(&reducers, &mut store, &mint_block(None)), Ok(vec![1]));
+        assert_eq!(store.accounts.get(&addr(1)), Some(50));
+    }
+
+    #[test]
+    fn block_with_a_caller_is_authorized_again_on_replay() {
+        let reducers = ReducerRegistry::with_defaults(b"test".to_vec());
+        let mut store = owned_store();
+        let err = replay_block(&reducers, &mut store, &mint_block(Some(addr(2)))).unwrap_err();
+        assert_eq!(err.action, 0);
+        assert!(matches!(err.replayed, ApplyStatus::Err { .. }));
+    }
+
+    #[test]
+    fn first_block_with_a_parent_is_a_broken_link() {
+        let mut store = mem::store(usize::MAX);
+        let block = Block {
+            header: Some(BlockHeader {
+                height: 0,
+                parent_hash: Some(vec![0; 32]),
+                timestamp_ns: None,
+                actions_root: actions_root(&[]).to_vec(),
+                state_root: vec![0; 32],
+                events_range: EventRange::default(),
+                events_per_action: vec![],
+            }),
+            actions: vec![],
+            results: vec![],
+            caller: None,
+        };
+        let state = state_root::after_block(&store);
+        append_block(&mut store, &block, &state);
+        let err = verify_chain(&store, 0..1).unwrap_err();
+        assert_eq!(err, ChainError::BrokenLink { height: 0 });
+        assert_eq!(err.to_string(), "block 0 names a parent but is the first block");
+    }
+
+    #[test]
+    fn tip_without_a_usable_hash_is_not_compared() {
+        let mut store = mem::store(usize::MAX);
+        let block = mint_block(None);
+        let state = state_root::after_block(&store);
+        append_block(&mut store, &block, &state);
+        for hash in [vec![], vec![1; 31]] {
+            store.tip.set(ChainTip {
+                height: 0,
+                hash,
+                balances: vec![],
+            });
+            assert_eq!(verify_chain(&store, 0..1), Ok(()));
+            assert_eq!(tip_hash(&store), Some(block.hash()));
+        }
+        store.tip.set(ChainTip {
+            height: 0,
+            hash: vec![1; 32],
+            balances: vec![],
+        });
+        assert_eq!(verify_chain(&store, 0..1), Err(ChainError::TipMismatch { height: 0 }));
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt;
+
+use ed25519_dalek::Verifier as _;
+
+use crate::types::{
+    actions::Action,
+    address::Address,
+    meta::Meta,
+    signed::{PublicKey, SignedAction},
+};
+
+// Keeps signatures over actions from being valid for any other message format.
+const SIGNING_DOMAIN: &[u8] = b"appchain/signed-action/v1";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthError {
+    MalformedPublicKey,
+    MalformedSignature,
+    BadSignature,
+    NestedEnvelope,
+    Unsigned,
+    BadNonce { expected: u64, got: u64 },
+    NonceExhausted,
+    NotAuthorized { signer: Address, required: Address },
+    NotOwner,
+    NotMinter,
+    NotPendingOwner,
+    OwnerAlreadySet,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MalformedPublicKey => write!(f, "malformed public key"),
+            AuthError::MalformedSignature => write!(f, "malformed signature"),
+            AuthError::BadSignature => write!(f, "signature does not verify"),
+            AuthError::NestedEnvelope => write!(f, "signed action wraps another signed action"),
+            AuthError::Unsigned => write!(f, "action must be signed"),
+            AuthError::BadNonce { expected, got } => {
+                write!(f, "bad nonce: expected {expected}, got {got}")
+            }
+            AuthError::NonceExhausted => write!(f, "signer nonce exhausted"),
+            AuthError::NotAuthorized { signer, required } => {
+                write!(f, "signer {signer} is not {required}")
+            }
+            AuthError::NotOwner => write!(f, "only the chain owner may do this"),
+            AuthError::NotMinter => write!(f, "only the owner or a minter may mint"),
+            AuthError::NotPendingOwner => write!(f, "only the proposed owner may accept"),
+            AuthError::OwnerAlreadySet => write!(f, "chain already has an owner"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// The bytes a `SignedAction` signature covers: a domain prefix, the length-prefixed
+/// chain id, the nonce, then the candid encoding of the action.
+pub fn signing_message(chain_id: &[u8], action: &Action, nonce: u64) -> Vec<u8> {
+    let mut msg = SIGNING_DOMAIN.to_vec();
+    msg.extend_from_slice(&(chain_id.len() as u32).to_be_bytes());
+    msg.extend_from_slice(chain_id);
+    msg.extend_from_slice(&nonce.to_be_bytes());
+    msg.extend(candid::encode_one(action).expect("encode action"));
+    msg
+}
+
+/// Fails unless `who` is the chain owner. Nobody passes on a chain without one.
+pub fn require_owner(meta: &Meta, who: Option<&Address>) -> Result<(), AuthError> {
+    match (&meta.owner, who) {
+        (Some(owner), Some(w)) if owner == w => Ok(()),
+        _ => Err(AuthError::NotOwner),
+    }
+}
+
+/// Fails unless `who` is the owner or in the minter set.
+pub fn require_minter(meta: &Meta, who: Option<&Address>) -> Result<(), AuthError> {
+    let minters = meta.minters.as_deref().unwrap_or_default();
+    match who {
+        Some(w) if meta.owner.as_ref() == Some(w) || minters.contains(w) => Ok(()),
+        _ => Err(AuthError::NotMinter),
+    }
+}
+
+/// Checks the envelope's signature for `chain_id` and returns the signer's address.
+pub fn verify(signed: &SignedAction, chain_id: &[u8]) -> Result<Address, AuthError> {
+    if matches!(signed.action, Action::Signed(_)) {
+        return Err(AuthError::NestedEnvelope);
+    }
+    let msg = signing_message(chain_id, &signed.action, signed.nonce);
+    match &signed.public_key {
+        PublicKey::Ed25519(key) => {
+            let key: &[u8; 32] = key
+                .as_slice()
+                .try_into()
+                .map_err(|_| AuthError::MalformedPublicKey)?;
+            let vk = ed25519_dalek::VerifyingKey::from_bytes(key)
+                .map_err(|_| AuthError::MalformedPublicKey)?;
+            let sig = ed25519_dalek::Signature::from_slice(&signed.signature)
+                .map_err(|_| AuthError::MalformedSignature)?;
+            vk.verify(&msg, &sig).map_err(|_| AuthError::BadSignature)?;
+        }
+        PublicKey::Secp256k1(key) => {
+            let vk = k256::ecdsa::VerifyingKey::from_sec1_bytes(key)
+                .map_err(|_| AuthError::MalformedPublicKey)?;
+            let sig = k256::ecdsa::Signature::from_slice(&signed.signature)
+                .map_err(|_| AuthError::MalformedSignature)?;
+            vk.verify(&msg, &sig).map_err(|_| AuthError::BadSignature)?;
+        }
+    }
+    Ok(Address::from_public_key(&signed.public_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::Signer as _;
+
+    use super::*;
+    use crate::types::actions::MetaAction;
+
+    fn signed_for(chain_id: &[u8]) -> SignedAction {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let action = Action::Meta(MetaAction::BumpCounter);
+        let signature = key.sign(&signing_message(chain_id, &action, 3));
+        SignedAction {
+            action,
+            public_key: PublicKey::Ed25519(key.verifying_key().to_bytes().to_vec()),
+            signature: signature.to_bytes().to_vec(),
+            nonce: 3,
+        }
+    }
+
+    #[test]
+    fn signature_is_bound_to_its_chain() {
+        let signed = signed_for(b"chain-a");
+        assert_eq!(
+            verify(&signed, b"chain-a"),
+            Ok(Address::from_public_key(&signed.public_key))
+        );
+        assert_eq!(verify(&signed, b"chain-b"), Err(AuthError::BadSignature));
+    }
+}
@@ -1,3 +1,6 @@
 pub mod reducer;
 pub mod types;
 pub mod store;
+pub mod apply;
+pub mod auth;
+pub mod state_root;
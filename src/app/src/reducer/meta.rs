@@ -1,40 +1,138 @@
-use crate::store::StoreGeneric;
+use super::{ActionContext, Reducer};
+use crate::auth::{self, AuthError};
+use crate::store::{Backends, StoreGeneric};
 use crate::types::{
     actions::{Action, ApplyStatus, MetaAction},
-    address::Address,
     events::{Event, MetaEvent},
-    meta::Meta,
 };
-use staging_memory::traits::{CellStore, LogStore, MapStore};
 
-pub fn reduce<A, B, C, D>(
-    store: &mut StoreGeneric<A, B, C, D>,
-    action: &Action,
-) -> ApplyStatus
-where
-    A: MapStore<Address, u128>,
-    B: CellStore<Meta>,
-    C: LogStore<Event>,
-    D: LogStore<Vec<u8>>,
-{
-    match action {
-        Action::Meta(MetaAction::SetChainName { name }) => {
-            let mut m = store.meta.get().unwrap_or_else(Meta::default);
-            m.chain_name = name.clone();
-            store.meta.set(m.clone());
-            store.events.append(Event::Meta(MetaEvent::SetChainName { name: m.chain_name }));
-            ApplyStatus::Ok
+pub struct MetaReducer;
+
+impl<S: Backends> Reducer<S> for MetaReducer {
+    fn name(&self) -> &'static str {
+        "meta"
+    }
+
+    fn handles(&self, action: &Action) -> bool {
+        matches!(action, Action::Meta(_))
+    }
+
+    fn apply(
+        &self,
+        store: &mut StoreGeneric<S>,
+        ctx: &ActionContext,
+        action: &Action,
+    ) -> ApplyStatus {
+        if let Action::Meta(meta_action) = action {
+            if owner_only(meta_action) && !ctx.preauthorized {
+                let m = store.meta.get().unwrap_or_default();
+                if let Err(e) = auth::require_owner(&m, ctx.principal()) {
+                    return e.into();
+                }
+            }
         }
-        Action::Meta(MetaAction::BumpCounter) => {
-            let mut m = store.meta.get().unwrap_or_else(Meta::default);
-            m.counter = m.counter.saturating_add(1);
-            let new_counter = m.counter;
-            store.meta.set(m);
-            store
-                .events
-                .append(Event::Meta(MetaEvent::BumpCounter { new_counter }));
-            ApplyStatus::Ok
+        match action {
+            Action::Meta(MetaAction::SetChainName { name }) => {
+                let mut m = store.meta.get().unwrap_or_default();
+                m.chain_name = name.clone();
+                store.meta.set(m.clone());
+                store.events.append(Event::Meta(MetaEvent::SetChainName { name: m.chain_name }));
+                ApplyStatus::Ok
+            }
+            Action::Meta(MetaAction::BumpCounter) => {
+                let mut m = store.meta.get().unwrap_or_default();
+                m.counter = m.counter.saturating_add(1);
+                let new_counter = m.counter;
+                store.meta.set(m);
+                store
+                    .events
+                    .append(Event::Meta(MetaEvent::BumpCounter { new_counter }));
+                ApplyStatus::Ok
+            }
+            Action::Meta(MetaAction::SetBlockPolicy { policy }) => {
+                let mut m = store.meta.get().unwrap_or_default();
+                m.block_policy = Some(*policy);
+                store.meta.set(m);
+                store
+                    .events
+                    .append(Event::Meta(MetaEvent::SetBlockPolicy { policy: *policy }));
+                ApplyStatus::Ok
+            }
+            Action::Meta(MetaAction::SetFees { fees }) => {
+                let mut m = store.meta.get().unwrap_or_default();
+                m.fees = fees.clone();
+                store.meta.set(m);
+                store
+                    .events
+                    .append(Event::Meta(MetaEvent::SetFees { fees: fees.clone() }));
+                ApplyStatus::Ok
+            }
+            Action::Meta(MetaAction::SetMinters { minters }) => {
+                let mut m = store.meta.get().unwrap_or_default();
+                m.minters = Some(minters.clone());
+                store.meta.set(m);
+                store.events.append(Event::Meta(MetaEvent::SetMinters {
+                    minters: minters.clone(),
+                }));
+                ApplyStatus::Ok
+            }
+            Action::Meta(MetaAction::InitOwner { owner }) => {
+                let mut m = store.meta.get().unwrap_or_default();
+                if m.owner.is_some() {
+                    return AuthError::OwnerAlreadySet.into();
+                }
+                m.owner = Some(owner.clone());
+                store.meta.set(m);
+                store.events.append(Event::Meta(MetaEvent::InitOwner {
+                    owner: owner.clone(),
+                }));
+                ApplyStatus::Ok
+            }
+            Action::Meta(MetaAction::ProposeOwner { owner }) => {
+                let mut m = store.meta.get().unwrap_or_default();
+                m.pending_owner = Some(owner.clone());
+                store.meta.set(m);
+                store.events.append(Event::Meta(MetaEvent::ProposeOwner {
+                    owner: owner.clone(),
+                }));
+                ApplyStatus::Ok
+            }
+            Action::Meta(MetaAction::AcceptOwnership) => {
+                let mut m = store.meta.get().unwrap_or_default();
+                let owner = match (&m.pending_owner, ctx.principal()) {
+                    (Some(pending), Some(who)) if pending == who => pending.clone(),
+                    _ => return AuthError::NotPendingOwner.into(),
+                };
+                let previous = m.owner.replace(owner.clone());
+                m.pending_owner = None;
+                store.meta.set(m);
+                store
+                    .events
+                    .append(Event::Meta(MetaEvent::AcceptOwnership { previous, owner }));
+                ApplyStatus::Ok
+            }
+            Action::Meta(MetaAction::RenounceOwnership) => {
+                let mut m = store.meta.get().unwrap_or_default();
+                let Some(previous) = m.owner.take() else {
+                    return AuthError::NotOwner.into();
+                };
+                m.pending_owner = None;
+                store.meta.set(m);
+                store
+                    .events
+                    .append(Event::Meta(MetaEvent::RenounceOwnership { previous }));
+                ApplyStatus::Ok
+            }
+            _ => ApplyStatus::Pass { reason: "skipped by meta".into() },
         }
-        _ => ApplyStatus::Pass { reason: "skipped by meta".into() },
     }
 }
+
+// Configuration changes need the owner. Accepting needs the pending owner instead, and
+// `InitOwner` is only valid while there is no owner to ask.
+fn owner_only(action: &MetaAction) -> bool {
+    !matches!(
+        action,
+        MetaAction::BumpCounter | MetaAction::AcceptOwnership | MetaAction::InitOwner { .. }
+    )
+}
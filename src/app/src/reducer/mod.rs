@@ -1,41 +1,163 @@
 pub mod ledger;
 pub mod meta;
 
-use crate::store::StoreGeneric;
+use crate::auth::{self, AuthError};
+use crate::store::{Backends, StoreGeneric};
 use crate::types::{
     actions::{Action, ApplyStatus},
     address::Address,
-    events::Event,
-    meta::Meta,
 };
-use staging_memory::traits::{CellStore, LogStore, MapStore};
-
-pub fn reduce_in_order<A, B, C, D>(
-    store: &mut StoreGeneric<A, B, C, D>,
-    action: &Action,
-) -> ApplyStatus
-where
-    A: MapStore<Address, u128>,
-    B: CellStore<Meta>,
-    C: LogStore<Event>,
-    D: LogStore<Vec<u8>>,
-{
-    let mut saw_ok = false;
-    let mut err: Option<String> = None;
-    for reducer in [ledger::reduce::<A, B, C, D>, meta::reduce::<A, B, C, D>] {
-        match reducer(store, action) {
-            ApplyStatus::Ok => saw_ok = true,
-            ApplyStatus::Pass { .. } => {}
-            ApplyStatus::Err { error } => err = Some(error),
+
+/// Who an action is being applied on behalf of.
+#[derive(Clone, Debug, Default)]
+pub struct ActionContext {
+    /// Whoever submitted the block, as recorded in it (the IC caller on the canister).
+    pub caller: Option<Address>,
+    /// Address of the key that signed the action, if it arrived in a `SignedAction`.
+    pub signer: Option<Address>,
+    /// Block time in nanoseconds, as recorded in the block.
+    pub timestamp_ns: Option<u64>,
+    /// Set when replaying a block recorded before actions were bound to a caller. Those
+    /// actions were applied without authorization checks, so replay skips them too.
+    pub preauthorized: bool,
+}
+
+impl ActionContext {
+    /// Context shared by every action in a block.
+    pub fn for_block(caller: Option<Address>, timestamp_ns: Option<u64>) -> Self {
+        Self {
+            caller,
+            timestamp_ns,
+            ..Self::default()
+        }
+    }
+
+    /// The identity the action acts as: its signer if signed, otherwise the block's caller.
+    pub fn principal(&self) -> Option<&Address> {
+        self.signer.as_ref().or(self.caller.as_ref())
+    }
+}
+
+/// A unit of state transition logic. Reducers only see actions they `handles`;
+/// signed envelopes are verified and unwrapped before they get there.
+pub trait Reducer<S: Backends> {
+    fn name(&self) -> &'static str;
+    fn handles(&self, action: &Action) -> bool;
+    fn apply(
+        &self,
+        store: &mut StoreGeneric<S>,
+        ctx: &ActionContext,
+        action: &Action,
+    ) -> ApplyStatus;
+}
+
+/// The reducers an app runs, in the order they are registered, for one chain.
+pub struct ReducerRegistry<S: Backends> {
+    reducers: Vec<Box<dyn Reducer<S>>>,
+    /// Identifies the deployment signed actions are bound to (the canister id on the IC),
+    /// so a signature for one chain is not valid on another.
+    chain_id: Vec<u8>,
+}
+
+impl<S: Backends> ReducerRegistry<S> {
+    pub fn new(chain_id: impl Into<Vec<u8>>) -> Self {
+        Self {
+            reducers: Vec::new(),
+            chain_id: chain_id.into(),
         }
     }
-    if let Some(e) = err {
-        ApplyStatus::Err { error: e }
-    } else if saw_ok {
-        ApplyStatus::Ok
-    } else {
-        ApplyStatus::Pass {
-            reason: "no reducer handled action".into(),
+
+    /// The built-in ledger and meta reducers.
+    pub fn with_defaults(chain_id: impl Into<Vec<u8>>) -> Self {
+        let mut registry = Self::new(chain_id);
+        registry.register(ledger::LedgerReducer).register(meta::MetaReducer);
+        registry
+    }
+
+    pub fn register<R: Reducer<S> + 'static>(&mut self, reducer: R) -> &mut Self {
+        self.reducers.push(Box::new(reducer));
+        self
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.reducers.iter().map(|r| r.name()).collect()
+    }
+
+    pub fn chain_id(&self) -> &[u8] {
+        &self.chain_id
+    }
+
+    /// Runs the action in its own layer: kept if every reducer returns `Ok` or `Pass`,
+    /// reverted on the first `Err` so no reducer's partial writes leak into the block.
+    /// A `SignedAction` whose signature does not verify is rejected before any reducer runs;
+    /// one that does consumes its signer's nonce in the action's layer.
+    pub fn reduce_in_order(
+        &self,
+        store: &mut StoreGeneric<S>,
+        ctx: &ActionContext,
+        action: &Action,
+    ) -> ApplyStatus {
+        let mut ctx = ctx.clone();
+        let (action, nonce) = match action {
+            Action::Signed(signed) => match auth::verify(signed, &self.chain_id) {
+                Ok(signer) => {
+                    ctx.signer = Some(signer);
+                    (&signed.action, Some(signed.nonce))
+                }
+                Err(e) => return e.into(),
+            },
+            other => (other, None),
+        };
+        let mut saw_ok = false;
+        let mut err: Option<String> = None;
+        store.push_layer();
+        if let (Some(signer), Some(nonce)) = (&ctx.signer, nonce) {
+            if let Err(e) = consume_nonce(store, signer, nonce) {
+                err = Some(e.to_string());
+            }
+        }
+        for reducer in self
+            .reducers
+            .iter()
+            .filter(|r| err.is_none() && r.handles(action))
+        {
+            match reducer.apply(store, &ctx, action) {
+                ApplyStatus::Ok => saw_ok = true,
+                ApplyStatus::Pass { .. } => {}
+                ApplyStatus::Err { error } => {
+                    err = Some(error);
+                    break;
+                }
+            }
+        }
+        if err.is_some() {
+            store.revert_top();
+        } else {
+            store.commit_top();
+        }
+        if let Some(e) = err {
+            ApplyStatus::Err { error: e }
+        } else if saw_ok {
+            ApplyStatus::Ok
+        } else {
+            ApplyStatus::Pass {
+                reason: "no reducer handled action".into(),
+            }
         }
     }
 }
+
+/// Checks a nonce against the signer's next expected one and advances it.
+fn consume_nonce<S: Backends>(
+    store: &mut StoreGeneric<S>,
+    signer: &Address,
+    got: u64,
+) -> Result<(), AuthError> {
+    let expected = store.nonces.get(signer).unwrap_or(0);
+    if got != expected {
+        return Err(AuthError::BadNonce { expected, got });
+    }
+    let next = expected.checked_add(1).ok_or(AuthError::NonceExhausted)?;
+    store.nonces.insert(signer.clone(), next);
+    Ok(())
+}
@@ -0,0 +1,282 @@
+//! The state root each block header commits to: a hash over every account balance and
+//! `Meta`, kept up to date from the accounts a block touches rather than by rescanning.
+//!
+//! Balances are folded into a 256-bit sum of per-entry hashes, so a changed entry only
+//! subtracts its old hash and adds its new one. The root then hashes that sum with
+//! `Meta`'s ICRC-3 value. It lets two parties check they computed the same state; it is
+//! not meant as a proof of any single balance.
+
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::store::{Backends, StoreGeneric};
+use crate::types::{
+    address::Address,
+    token::{AccountKey, TokenId},
+    value::{Hash, Value},
+};
+
+/// The state after a block: its root, and the balance sum the next block starts from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockState {
+    pub root: Hash,
+    pub balances: Hash,
+}
+
+/// State after the block whose changes make up the top layer of `store`. Starts from the
+/// balance sum stored with the tip; logs written before the tip was stored fall back to
+/// summing every balance once.
+pub fn after_block<S: Backends>(store: &StoreGeneric<S>) -> BlockState {
+    let len = store.blocks.len() as u64;
+    let stored = match store.tip.get() {
+        _ if len == 0 => Some([0u8; 32]),
+        Some(tip) if tip.height + 1 == len => Hash::try_from(tip.balances.as_slice()).ok(),
+        _ => None,
+    };
+    let balances = match stored {
+        Some(sum) => apply_top_layer(store, sum),
+        None => full_sum(store),
+    };
+    BlockState {
+        root: root(store, &balances),
+        balances,
+    }
+}
+
+/// Where the state of a replayed store disagrees with the chain it was replayed from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TipStateError {
+    /// Summing every balance gives a different sum than the one stored with the tip.
+    Balances { height: u64 },
+    /// The state does not match the state root in the last block's header.
+    StateRoot { height: u64 },
+}
+
+impl fmt::Display for TipStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TipStateError::Balances { height } => {
+                write!(f, "balances do not add up to the sum stored with tip {height}")
+            }
+            TipStateError::StateRoot { height } => {
+                write!(f, "state does not match the state root of block {height}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TipStateError {}
+
+/// Checks the whole state against the tip, summing every balance from scratch: the sum
+/// must equal the one kept incrementally with the tip, and the root the newest block's
+/// state root. Meant for after a replay, when the state was rebuilt from the blocks.
+pub fn check_tip<S: Backends>(store: &StoreGeneric<S>) -> Result<(), TipStateError> {
+    let Some(height) = (store.blocks.len() as u64).checked_sub(1) else {
+        return Ok(());
+    };
+    let balances = full_sum(store);
+    // A tip stored without a balance sum has nothing to check, as in `after_block`.
+    let stored = store
+        .tip
+        .get()
+        .filter(|t| t.height == height)
+        .and_then(|t| Hash::try_from(t.balances.as_slice()).ok());
+    if stored.is_some_and(|sum| sum != balances) {
+        return Err(TipStateError::Balances { height });
+    }
+    let last = crate::apply::tip_block(store).expect("log is not empty");
+    if let Some(header) = last.header {
+        if header.state_root != root(store, &balances) {
+            return Err(TipStateError::StateRoot { height });
+        }
+    }
+    Ok(())
+}
+
+fn root<S: Backends>(store: &StoreGeneric<S>, balances: &Hash) -> Hash {
+    let meta = Value::from(&store.meta.get().unwrap_or_default()).hash();
+    let mut h = Sha256::new();
+    h.update(b"appchain/state-root");
+    h.update(balances);
+    h.update(meta);
+    h.finalize().into()
+}
+
+fn apply_top_layer<S: Backends>(store: &StoreGeneric<S>, mut sum: Hash) -> Hash {
+    for owner in store.accounts.top_keys() {
+        let old = store.accounts.get_below_top(&owner);
+        let new = store.accounts.get(&owner);
+        update(&mut sum, &native_key(&owner), old, new);
+    }
+    for key in store.token_accounts.top_keys() {
+        let old = store.token_accounts.get_below_top(&key);
+        let new = store.token_accounts.get(&key);
+        update(&mut sum, &token_key(&key), old, new);
+    }
+    sum
+}
+
+fn full_sum<S: Backends>(store: &StoreGeneric<S>) -> Hash {
+    let mut sum = [0u8; 32];
+    for (owner, bal) in store.accounts.iter_effective() {
+        add(&mut sum, &entry_hash(&native_key(&owner), bal));
+    }
+    for (key, bal) in store.token_accounts.iter_effective() {
+        add(&mut sum, &entry_hash(&token_key(&key), bal));
+    }
+    sum
+}
+
+fn update(sum: &mut Hash, key: &[u8], old: Option<u128>, new: Option<u128>) {
+    if old == new {
+        return;
+    }
+    if let Some(old) = old {
+        sub(sum, &entry_hash(key, old));
+    }
+    if let Some(new) = new {
+        add(sum, &entry_hash(key, new));
+    }
+}
+
+fn native_key(owner: &Address) -> Vec<u8> {
+    token_key(&AccountKey {
+        token: TokenId::NATIVE,
+        owner: owner.clone(),
+    })
+}
+
+fn token_key(key: &AccountKey) -> Vec<u8> {
+    let mut bytes = key.token.0.to_be_bytes().to_vec();
+    bytes.extend_from_slice(key.owner.as_bytes());
+    bytes
+}
+
+fn entry_hash(key: &[u8], balance: u128) -> Hash {
+    let mut h = Sha256::new();
+    h.update(b"appchain/balance");
+    h.update((key.len() as u32).to_be_bytes());
+    h.update(key);
+    h.update(balance.to_be_bytes());
+    h.finalize().into()
+}
+
+// Big-endian addition and subtraction modulo 2^256.
+fn add(sum: &mut Hash, x: &Hash) {
+    let mut carry = 0u16;
+    for (s, x) in sum.iter_mut().zip(x).rev() {
+        let v = u16::from(*s) + u16::from(*x) + carry;
+        *s = v as u8;
+        carry = v >> 8;
+    }
+}
+
+fn sub(sum: &mut Hash, x: &Hash) {
+    let mut borrow = 0i16;
+    for (s, x) in sum.iter_mut().zip(x).rev() {
+        let v = i16::from(*s) - i16::from(*x) - borrow;
+        *s = v.rem_euclid(256) as u8;
+        borrow = i16::from(v < 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apply::apply_block;
+    use crate::reducer::{ActionContext, ReducerRegistry};
+    use crate::store::mem::{self, addr};
+    use crate::types::actions::{Action, ApplyStatus, LedgerAction};
+    use crate::types::block::{Block, ChainTip};
+
+    fn acct(token: u64, owner: u8) -> AccountKey {
+        AccountKey {
+            token: TokenId(token),
+            owner: addr(owner),
+        }
+    }
+
+    #[test]
+    fn top_layer_delta_matches_full_sum_across_spilled_layers() {
+        // Layers spill to scratch past two staged entries.
+        let mut store = mem::store(2);
+        for b in 0..4 {
+            store.accounts.insert(addr(b), 100 + u128::from(b));
+            store.token_accounts.insert(acct(1, b), 10);
+        }
+        store.commit_all();
+        let base = full_sum(&store);
+
+        store.push_layer();
+        for b in 2..6 {
+            store.accounts.insert(addr(b), 7);
+        }
+        store.token_accounts.insert(acct(1, 9), 1);
+        let below = full_sum(&store);
+        store.push_layer();
+        store.accounts.insert(addr(0), 1);
+        store.accounts.remove(&addr(1));
+        store.accounts.insert(addr(3), 7);
+        store.accounts.insert(addr(8), 5);
+        store.token_accounts.remove(&acct(1, 0));
+        store.token_accounts.insert(acct(2, 0), 3);
+        store.token_accounts.insert(acct(1, 2), 11);
+        assert!(store.accounts.layers()[1].spilled.is_some());
+        assert!(store.accounts.layers()[2].spilled.is_some());
+        assert!(store.token_accounts.layers()[2].spilled.is_some());
+        assert_eq!(apply_top_layer(&store, below), full_sum(&store));
+
+        // Merged into the spilled layer below, the delta is taken from the base.
+        store.commit_top();
+        assert_eq!(apply_top_layer(&store, base), full_sum(&store));
+    }
+
+    #[test]
+    fn check_tip_catches_state_the_blocks_do_not_explain() {
+        let reducers = ReducerRegistry::with_defaults(b"test".to_vec());
+        let mut store = mem::store(usize::MAX);
+        let mut ctx = ActionContext::for_block(None, None);
+        ctx.preauthorized = true;
+        for b in 1..3 {
+            let mint = Action::Ledger(LedgerAction::Coinbase {
+                to: addr(b),
+                amount: 50,
+                token: None,
+            });
+            let outcome = apply_block(&reducers, &mut store, ctx.clone(), vec![mint]);
+            assert_eq!(outcome.height, Some(u64::from(b) - 1));
+            store.commit_all();
+        }
+        assert_eq!(check_tip(&store), Ok(()));
+
+        store.accounts.insert(addr(1), 49);
+        assert_eq!(check_tip(&store), Err(TipStateError::Balances { height: 1 }));
+    }
+
+    #[test]
+    fn check_tip_passes_over_a_legacy_tip_without_balances() {
+        // One block from before headers, with a tip stored before it kept a balance sum.
+        let mut store = mem::store(usize::MAX);
+        let block = Block {
+            header: None,
+            actions: vec![Action::Ledger(LedgerAction::Coinbase {
+                to: addr(1),
+                amount: 50,
+                token: None,
+            })],
+            results: vec![ApplyStatus::Ok],
+            caller: None,
+        };
+        store.accounts.insert(addr(1), 50);
+        store.blocks.append(candid::encode_one(&block).unwrap());
+        store.tip.set(ChainTip {
+            height: 0,
+            hash: block.hash().to_vec(),
+            balances: vec![],
+        });
+        store.commit_all();
+        assert_eq!(check_tip(&store), Ok(()));
+        assert_eq!(after_block(&store).balances, full_sum(&store));
+    }
+}
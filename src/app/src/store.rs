@@ -1,78 +1,470 @@
+use std::fmt;
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
 use staging_memory::{
-    btree::BTreeTxn, log::LogTxn, struct_store::StructTxn, traits::{CellStore, LogStore, MapStore}
+    btree::BTreeTxn, log::LogTxn, overlay::Overlay, struct_store::StructTxn, traits::{CellStore, Layered, LogStore, MapStore}
+};
+use crate::types::{
+    address::Address,
+    allowance::{Allowance, AllowanceKey},
+    block::ChainTip,
+    events::Event,
+    layer::LayerTag,
+    meta::Meta,
+    token::{AccountKey, TokenId, TokenInfo},
 };
-use crate::types::{address::Address, block::Block, events::Event, meta::Meta};
-
-#[derive(Debug)]
-pub struct StoreGeneric<A, B, C, D>
-where
-    A: MapStore<Address, u128>,
-    B: CellStore<Meta>,
-    C: LogStore<Event>,
-    D: LogStore<Vec<u8>>,
-{
-    pub accounts: BTreeTxn<Address, u128, A>,
-    pub meta: StructTxn<Meta, B>,
-    pub events: LogTxn<Event, C>,
-    pub blocks: LogTxn<Vec<u8>, D>,
+
+/// Uncommitted layers of every txn in the store, oldest first.
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
+pub struct StagedLayers {
+    pub accounts: Vec<Overlay<Address, u128>>,
+    pub nonces: Vec<Overlay<Address, u64>>,
+    pub token_accounts: Vec<Overlay<AccountKey, u128>>,
+    pub tokens: Vec<Overlay<TokenId, TokenInfo>>,
+    pub allowances: Vec<Overlay<AllowanceKey, Allowance>>,
+    pub meta: Vec<Option<Meta>>,
+    pub events: Vec<Vec<Event>>,
+    pub blocks: Vec<Vec<Vec<u8>>>,
+    pub tip: Vec<Option<ChainTip>>,
+    pub tags: Vec<Option<LayerTag>>,
 }
 
-impl<A, B, C, D> StoreGeneric<A, B, C, D>
-where
-    A: MapStore<Address, u128>,
-    B: CellStore<Meta>,
-    C: LogStore<Event>,
-    D: LogStore<Vec<u8>>,
-{
-    pub fn new(accounts_base: A, meta_base: B, events_base: C, blocks_base: D) -> Self {
+impl StagedLayers {
+    // Every part must hold one layer per tag.
+    fn check_depth(&self) -> Result<(), LayerCountMismatch> {
+        let depth = self.tags.len();
+        let parts = [
+            ("accounts", self.accounts.len()),
+            ("nonces", self.nonces.len()),
+            ("token_accounts", self.token_accounts.len()),
+            ("tokens", self.tokens.len()),
+            ("allowances", self.allowances.len()),
+            ("meta", self.meta.len()),
+            ("events", self.events.len()),
+            ("blocks", self.blocks.len()),
+            ("tip", self.tip.len()),
+        ];
+        match parts.into_iter().find(|(_, layers)| *layers != depth) {
+            Some((part, layers)) => Err(LayerCountMismatch {
+                part,
+                tags: depth,
+                layers,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `StagedLayers` in which a part holds a different number of layers than there are tags.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayerCountMismatch {
+    pub part: &'static str,
+    pub tags: usize,
+    pub layers: usize,
+}
+
+impl fmt::Display for LayerCountMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "staged {} has {} layers but there are {} tags",
+            self.part, self.layers, self.tags
+        )
+    }
+}
+
+impl std::error::Error for LayerCountMismatch {}
+
+/// The storage each part of a `StoreGeneric` sits on; implemented once per environment
+/// (stable memory in the canister, sled in the client) so code generic over the store
+/// needs a single type parameter.
+pub trait Backends {
+    type Accounts: MapStore<Address, u128>;
+    type Nonces: MapStore<Address, u64>;
+    type TokenAccounts: MapStore<AccountKey, u128>;
+    type Tokens: MapStore<TokenId, TokenInfo>;
+    type Allowances: MapStore<AllowanceKey, Allowance>;
+    type Meta: CellStore<Meta>;
+    type Events: LogStore<Event>;
+    type Blocks: LogStore<Vec<u8>>;
+    type Tip: CellStore<ChainTip>;
+}
+
+/// One base per part of the store, as handed to `StoreGeneric::new`.
+pub struct StoreBases<S: Backends> {
+    pub accounts: S::Accounts,
+    pub nonces: S::Nonces,
+    pub token_accounts: S::TokenAccounts,
+    pub tokens: S::Tokens,
+    pub allowances: S::Allowances,
+    pub meta: S::Meta,
+    pub events: S::Events,
+    pub blocks: S::Blocks,
+    pub tip: S::Tip,
+}
+
+pub struct StoreGeneric<S: Backends> {
+    /// Native token balances.
+    pub accounts: BTreeTxn<Address, u128, S::Accounts>,
+    /// Next expected nonce per signer; absent means 0.
+    pub nonces: BTreeTxn<Address, u64, S::Nonces>,
+    /// Balances of created tokens.
+    pub token_accounts: BTreeTxn<AccountKey, u128, S::TokenAccounts>,
+    pub tokens: BTreeTxn<TokenId, TokenInfo, S::Tokens>,
+    pub allowances: BTreeTxn<AllowanceKey, Allowance, S::Allowances>,
+    pub meta: StructTxn<Meta, S::Meta>,
+    pub events: LogTxn<Event, S::Events>,
+    pub blocks: LogTxn<Vec<u8>, S::Blocks>,
+    /// Hash of the newest block in `blocks`; see `apply::tip_hash`.
+    pub tip: StructTxn<ChainTip, S::Tip>,
+    tags: Vec<Option<LayerTag>>, // one per layer, top is last
+}
+
+impl<S: Backends> StoreGeneric<S> {
+    pub fn new(bases: StoreBases<S>) -> Self {
         Self {
-            accounts: BTreeTxn::new(accounts_base),
-            meta: StructTxn::new(meta_base),
-            events: LogTxn::new(events_base),
-            blocks: LogTxn::new(blocks_base),
+            accounts: BTreeTxn::new(bases.accounts),
+            nonces: BTreeTxn::new(bases.nonces),
+            token_accounts: BTreeTxn::new(bases.token_accounts),
+            tokens: BTreeTxn::new(bases.tokens),
+            allowances: BTreeTxn::new(bases.allowances),
+            meta: StructTxn::new(bases.meta),
+            events: LogTxn::new(bases.events),
+            blocks: LogTxn::new(bases.blocks),
+            tip: StructTxn::new(bases.tip),
+            tags: vec![None],
         }
     }
 
     pub fn push_layer(&mut self) {
         self.accounts.push_layer();
+        self.nonces.push_layer();
+        self.token_accounts.push_layer();
+        self.tokens.push_layer();
+        self.allowances.push_layer();
         self.meta.push_layer();
         self.events.push_layer();
         self.blocks.push_layer();
+        self.tip.push_layer();
+        self.tags.push(None);
+    }
+
+    pub fn push_tagged_layer(&mut self, tag: LayerTag) {
+        self.push_layer();
+        self.tag_top(tag);
+    }
+
+    pub fn tag_top(&mut self, tag: LayerTag) {
+        *self.tags.last_mut().expect("at least one layer") = Some(tag);
+    }
+
+    pub fn layer_count(&self) -> usize {
+        self.tags.len()
+    }
+
+    pub fn layer_tags(&self) -> &[Option<LayerTag>] {
+        &self.tags
     }
 
     pub fn revert_top(&mut self) {
         self.accounts.revert_top();
+        self.nonces.revert_top();
+        self.token_accounts.revert_top();
+        self.tokens.revert_top();
+        self.allowances.revert_top();
         self.meta.revert_top();
         self.events.revert_top();
         self.blocks.revert_top();
+        self.tip.revert_top();
+        if self.tags.len() > 1 {
+            self.tags.pop();
+        } else {
+            self.tags[0] = None;
+        }
     }
 
+    /// Merges the top layer into the one below. The merged layer keeps the lower layer's
+    /// tag, so `commit_through` and `revert_to` still find it, and a versioned base
+    /// records it at that tag's height; it takes the top layer's tag only if it had none.
     pub fn commit_top(&mut self) {
+        if self.tags.len() == 1 {
+            self.version_base_for_oldest();
+        }
         self.accounts.commit_top();
+        self.nonces.commit_top();
+        self.token_accounts.commit_top();
+        self.tokens.commit_top();
+        self.allowances.commit_top();
         self.meta.commit_top();
         self.events.commit_top();
         self.blocks.commit_top();
+        self.tip.commit_top();
+        if self.tags.len() > 1 {
+            let top = self.tags.pop().unwrap();
+            let below = self.tags.last_mut().unwrap();
+            if below.is_none() {
+                *below = top;
+            }
+        } else {
+            self.tags[0] = None;
+        }
     }
 
+    /// Commits oldest first so a versioned base records each tagged layer at its own height.
     pub fn commit_all(&mut self) {
-        self.accounts.commit_all();
-        self.meta.commit_all();
-        self.events.commit_all();
-        self.blocks.commit_all();
+        for _ in 0..self.tags.len() {
+            self.commit_oldest();
+        }
     }
 
     pub fn commit_oldest(&mut self) {
+        self.version_base_for_oldest();
         self.accounts.commit_oldest();
+        self.nonces.commit_oldest();
+        self.token_accounts.commit_oldest();
+        self.tokens.commit_oldest();
+        self.allowances.commit_oldest();
         self.meta.commit_oldest();
         self.events.commit_oldest();
         self.blocks.commit_oldest();
+        self.tip.commit_oldest();
+        self.tags.remove(0);
+        if self.tags.is_empty() {
+            self.tags.push(None);
+        }
+    }
+
+    // Untagged layers are recorded at whatever version the base is already on.
+    fn version_base_for_oldest(&mut self) {
+        if let Some(tag) = &self.tags[0] {
+            self.accounts.set_base_version(tag.height);
+        }
+    }
+
+    fn find_tag(&self, tag: &LayerTag) -> Option<usize> {
+        self.tags.iter().rposition(|t| t.as_ref() == Some(tag))
+    }
+
+    /// Commits every layer up to and including the one tagged `tag` into the base.
+    /// Returns false, committing nothing, if no layer carries the tag.
+    pub fn commit_through(&mut self, tag: &LayerTag) -> bool {
+        let Some(idx) = self.find_tag(tag) else {
+            return false;
+        };
+        for _ in 0..=idx {
+            self.commit_oldest();
+        }
+        true
+    }
+
+    /// Reverts every layer above the one tagged `tag`, keeping the tagged layer.
+    /// Returns false, reverting nothing, if no layer carries the tag.
+    pub fn revert_to(&mut self, tag: &LayerTag) -> bool {
+        let Some(idx) = self.find_tag(tag) else {
+            return false;
+        };
+        while self.tags.len() > idx + 1 {
+            self.revert_top();
+        }
+        true
+    }
+
+    pub fn export_layers(&self) -> StagedLayers {
+        StagedLayers {
+            accounts: self.accounts.layers().to_vec(),
+            nonces: self.nonces.layers().to_vec(),
+            token_accounts: self.token_accounts.layers().to_vec(),
+            tokens: self.tokens.layers().to_vec(),
+            allowances: self.allowances.layers().to_vec(),
+            meta: self.meta.layers().to_vec(),
+            events: self.events.layers().to_vec(),
+            blocks: self.blocks.layers().to_vec(),
+            tip: self.tip.layers().to_vec(),
+            tags: self.tags.clone(),
+        }
     }
 
+    /// Replaces the staged layers with `layers`. Rejects them, changing nothing, unless
+    /// every part holds one layer per tag.
+    pub fn restore_layers(&mut self, layers: StagedLayers) -> Result<(), LayerCountMismatch> {
+        layers.check_depth()?;
+        self.accounts.restore_layers(layers.accounts);
+        self.nonces.restore_layers(layers.nonces);
+        self.token_accounts.restore_layers(layers.token_accounts);
+        self.tokens.restore_layers(layers.tokens);
+        self.allowances.restore_layers(layers.allowances);
+        self.meta.restore_layers(layers.meta);
+        self.events.restore_layers(layers.events);
+        self.blocks.restore_layers(layers.blocks);
+        self.tip.restore_layers(layers.tip);
+        self.tags = layers.tags;
+        if self.tags.is_empty() {
+            self.tags.push(None);
+        }
+        Ok(())
+    }
+
+    /// Clears the whole store, blocks included, down to a single untagged layer.
+    pub fn clear_all(&mut self) {
+        self.clear_state_preserve_blocks();
+        self.blocks.clear_all();
+        self.tip.clear_all();
+        self.tags = vec![None];
+    }
+
+    /// Clears everything derived from the blocks; the blocks and their tip stay.
     pub fn clear_state_preserve_blocks(&mut self) {
         self.accounts.clear_all();
+        self.nonces.clear_all();
+        self.token_accounts.clear_all();
+        self.tokens.clear_all();
+        self.allowances.clear_all();
         self.meta.clear_all();
         self.events.clear_all();
     }
 }
 
+impl<S: Backends> Layered for StoreGeneric<S> {
+    fn push_layer(&mut self) {
+        StoreGeneric::push_layer(self)
+    }
+
+    fn commit_top(&mut self) {
+        StoreGeneric::commit_top(self)
+    }
+
+    fn revert_top(&mut self) {
+        StoreGeneric::revert_top(self)
+    }
+}
+
 // Concrete Store type and default_store are defined in the IC crate and client crate.
+
+/// An in-memory store for tests; balance layers spill past `threshold` staged entries.
+#[cfg(test)]
+pub(crate) mod mem {
+    use staging_memory::mem::{InMemoryCell, InMemoryLog, InMemoryMap, SpillingInMemoryMap};
+    use staging_memory::versioned::VersionedInMemoryMap;
+
+    use super::*;
+
+    pub struct MemBackends;
+
+    impl Backends for MemBackends {
+        // Versioned, like the canister's and the client's accounts.
+        type Accounts = SpillingInMemoryMap<Address, u128, VersionedInMemoryMap<Address, u128>>;
+        type Nonces = InMemoryMap<Address, u64>;
+        type TokenAccounts = SpillingInMemoryMap<AccountKey, u128>;
+        type Tokens = InMemoryMap<TokenId, TokenInfo>;
+        type Allowances = InMemoryMap<AllowanceKey, Allowance>;
+        type Meta = InMemoryCell<Meta>;
+        type Events = InMemoryLog<Event>;
+        type Blocks = InMemoryLog<Vec<u8>>;
+        type Tip = InMemoryCell<ChainTip>;
+    }
+
+    pub type MemStore = StoreGeneric<MemBackends>;
+
+    /// A 29-byte address filled with `b`.
+    pub fn addr(b: u8) -> Address {
+        Address(vec![b; 29])
+    }
+
+    pub fn store(threshold: usize) -> MemStore {
+        StoreGeneric::new(StoreBases {
+            accounts: SpillingInMemoryMap::over(VersionedInMemoryMap::new(), threshold),
+            nonces: InMemoryMap::new(),
+            token_accounts: SpillingInMemoryMap::new(threshold),
+            tokens: InMemoryMap::new(),
+            allowances: InMemoryMap::new(),
+            meta: InMemoryCell::new(),
+            events: InMemoryLog::new(),
+            blocks: InMemoryLog::new(),
+            tip: InMemoryCell::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commit_top_keeps_the_older_tag() {
+        let mut store = mem::store(usize::MAX);
+        store.push_tagged_layer(LayerTag::at_height(0));
+        store.blocks.append(vec![0]);
+        store.push_tagged_layer(LayerTag::at_height(1));
+        store.blocks.append(vec![1]);
+        store.commit_top();
+        assert_eq!(store.layer_tags(), &[None, Some(LayerTag::at_height(0))]);
+        assert!(!store.commit_through(&LayerTag::at_height(1)));
+        assert!(store.commit_through(&LayerTag::at_height(0)));
+        assert_eq!(store.layer_count(), 1);
+        assert_eq!(store.blocks.len(), 2);
+    }
+
+    #[test]
+    fn commit_top_into_an_untagged_layer_takes_the_top_tag() {
+        let mut store = mem::store(usize::MAX);
+        store.push_layer();
+        store.push_tagged_layer(LayerTag::at_height(0));
+        store.commit_top();
+        assert_eq!(store.layer_tags(), &[None, Some(LayerTag::at_height(0))]);
+    }
+
+    #[test]
+    fn restore_layers_rejects_a_part_with_the_wrong_depth() {
+        let mut store = mem::store(usize::MAX);
+        store.push_tagged_layer(LayerTag::at_height(0));
+        let mut layers = store.export_layers();
+        layers.events.pop();
+        let mut restored = mem::store(usize::MAX);
+        let err = restored.restore_layers(layers).unwrap_err();
+        assert_eq!(
+            err,
+            LayerCountMismatch {
+                part: "events",
+                tags: 2,
+                layers: 1,
+            }
+        );
+        assert_eq!(restored.layer_count(), 1);
+        assert!(restored.restore_layers(store.export_layers()).is_ok());
+        assert_eq!(restored.layer_tags(), store.layer_tags());
+    }
+
+    #[test]
+    fn tagged_commits_record_balances_by_height() {
+        let mut store = mem::store(usize::MAX);
+        let owner = mem::addr(1);
+        for (height, balance) in [(0, 10), (1, 25), (2, 40)] {
+            store.push_tagged_layer(LayerTag::at_height(height));
+            store.accounts.insert(owner.clone(), balance);
+            store.blocks.append(vec![height as u8]);
+            if height < 2 {
+                store.commit_all();
+            }
+        }
+        assert_eq!(store.accounts.get_at(&owner, 0), Some(10));
+        assert_eq!(store.accounts.get_at(&owner, 1), Some(25));
+        // Block 2 is still staged, so the base has nothing for it yet.
+        assert_eq!(store.accounts.get_at(&owner, 2), Some(25));
+        assert_eq!(crate::reducer::ledger::balance_at(&store, &owner, 1), Some(25));
+        assert_eq!(crate::reducer::ledger::balance_at(&store, &owner, 2), None);
+        store.commit_all();
+        assert_eq!(crate::reducer::ledger::balance_at(&store, &owner, 2), Some(40));
+    }
+
+    #[test]
+    fn clear_all_drops_staged_tags() {
+        let mut store = mem::store(usize::MAX);
+        store.push_tagged_layer(LayerTag::at_height(0));
+        store.blocks.append(vec![0]);
+        store.clear_all();
+        assert_eq!(store.layer_tags(), &[None]);
+        assert!(store.blocks.is_empty());
+        assert!(!store.commit_through(&LayerTag::at_height(0)));
+    }
+}
@@ -1,29 +1,62 @@
 use candid::CandidType;
 use serde::{Deserialize, Serialize};
 
-use super::address::Address;
+use crate::auth::AuthError;
+
+use super::{address::Address, block::BlockPolicy, fee::FeeSchedule, signed::SignedAction, token::TokenId};
 
 #[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
 pub enum LedgerAction {
-    Coinbase { to: Address, amount: u128 },
-    Transfer { from: Address, to: Address, amount: u128 },
+    /// `token` is `None` for the native token, here and in `Transfer`.
+    Coinbase { to: Address, amount: u128, token: Option<TokenId> },
+    Transfer { from: Address, to: Address, amount: u128, token: Option<TokenId> },
+    CreateToken { symbol: String, decimals: u8, minter: Option<Address> },
+    /// Lets `spender` move up to `amount` of the acting account's `token`, replacing
+    /// any earlier allowance.
+    Approve { spender: Address, amount: u128, expires_at: Option<u64>, token: Option<TokenId> },
+    TransferFrom {
+        spender: Address,
+        from: Address,
+        to: Address,
+        amount: u128,
+        token: Option<TokenId>,
+    },
+    /// Destroys `amount` of `from`'s `token`, reducing its supply.
+    Burn { from: Address, amount: u128, token: Option<TokenId> },
 }
 
 #[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
 pub enum MetaAction {
     SetChainName { name: String },
     BumpCounter,
+    SetBlockPolicy { policy: BlockPolicy },
+    SetFees { fees: Option<FeeSchedule> },
+    SetMinters { minters: Vec<Address> },
+    /// Sets the first owner of an unowned chain. Only submitted by the host at install.
+    InitOwner { owner: Address },
+    ProposeOwner { owner: Address },
+    AcceptOwnership,
+    RenounceOwnership,
 }
 
 #[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
 pub enum Action {
     Ledger(LedgerAction),
     Meta(MetaAction),
+    Signed(Box<SignedAction>),
 }
 
-#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
 pub enum ApplyStatus {
     Ok,
     Pass { reason: String },
     Err { error: String },
 }
+
+impl From<AuthError> for ApplyStatus {
+    fn from(e: AuthError) -> Self {
+        ApplyStatus::Err {
+            error: e.to_string(),
+        }
+    }
+}
@@ -1,5 +1,10 @@
+use std::fmt;
+
 use candid::CandidType;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::signed::PublicKey;
 
 #[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize, CandidType)]
 pub struct Address(pub Vec<u8>);
@@ -10,8 +15,50 @@ impl From<Vec<u8>> for Address {
     }
 }
 
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in &self.0 {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
 impl Address {
     pub fn as_bytes(&self) -> &[u8] {
         &self.0
     }
+
+    /// The account controlled by `key`: SHA-256 over the scheme byte and key bytes, so
+    /// the same bytes under different schemes never share an address.
+    pub fn from_public_key(key: &PublicKey) -> Self {
+        let mut h = Sha256::new();
+        h.update([key.scheme_byte()]);
+        h.update(key.as_bytes());
+        Address(h.finalize().to_vec())
+    }
+
+    /// The address of one of `owner`'s subaccounts, laid out like an ICRC-1 account:
+    /// the all-zero (default) subaccount is `owner` itself, any other is `owner ++ subaccount`.
+    pub fn with_subaccount(owner: &Address, subaccount: Option<&[u8; 32]>) -> Self {
+        match subaccount {
+            Some(sub) if *sub != [0u8; 32] => {
+                let mut bytes = owner.0.clone();
+                bytes.extend_from_slice(sub);
+                Address(bytes)
+            }
+            _ => owner.clone(),
+        }
+    }
+
+    /// Whether `principal` may act for this address: it is the address itself or one of
+    /// its subaccounts.
+    pub fn is_controlled_by(&self, principal: &Address) -> bool {
+        if self == principal {
+            return true;
+        }
+        !principal.0.is_empty()
+            && self.0.len() == principal.0.len() + 32
+            && self.0.starts_with(&principal.0)
+    }
 }
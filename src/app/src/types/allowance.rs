@@ -0,0 +1,33 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use super::{address::Address, token::TokenId};
+
+/// Key of what `spender` may move out of `owner`'s balance of `token`.
+#[derive(
+    Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, CandidType, Serialize, Deserialize,
+)]
+pub struct AllowanceKey {
+    pub token: TokenId,
+    pub owner: Address,
+    pub spender: Address,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct Allowance {
+    pub allowance: u128,
+    /// Block time in nanoseconds after which the allowance no longer applies.
+    pub expires_at: Option<u64>,
+}
+
+impl Allowance {
+    /// Without a block time an expiring allowance cannot be shown to be live, so it counts
+    /// as expired.
+    pub fn is_live(&self, now_ns: Option<u64>) -> bool {
+        match (self.expires_at, now_ns) {
+            (None, _) => true,
+            (Some(at), Some(now)) => now < at,
+            (Some(_), None) => false,
+        }
+    }
+}
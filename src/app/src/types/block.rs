@@ -1,10 +1,81 @@
 use candid::CandidType;
 use serde::{Deserialize, Serialize};
 
-use super::actions::{Action, ApplyStatus};
+use super::{
+    actions::{Action, ApplyStatus},
+    address::Address,
+};
 
 #[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
 pub struct Block {
+    /// Absent on blocks recorded before headers existed.
+    pub header: Option<BlockHeader>,
     pub actions: Vec<Action>,
     pub results: Vec<ApplyStatus>,
+    /// Who submitted the block; replay authorizes its unsigned actions as this caller.
+    pub caller: Option<Address>,
+}
+
+/// Where a block sits in the chain and what it committed to.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub height: u64,
+    /// Hash of the previous block; `None` for the first block.
+    pub parent_hash: Option<Vec<u8>>,
+    /// Block time in nanoseconds, as seen by whoever produced it; `None` if it had no clock.
+    pub timestamp_ns: Option<u64>,
+    /// ICRC-3 hash of the block's actions, in order.
+    pub actions_root: Vec<u8>,
+    /// Root of the balances and `Meta` after the block; see `state_root`.
+    pub state_root: Vec<u8>,
+    /// The events the block emitted, as indices into the event log.
+    pub events_range: EventRange,
+    /// How many of those events each action emitted, in order; they add up to the length
+    /// of `events_range`.
+    pub events_per_action: Vec<u32>,
+}
+
+/// The newest block and its hash, kept alongside the block log so the next block can
+/// chain to it without decoding the previous one.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct ChainTip {
+    pub height: u64,
+    pub hash: Vec<u8>,
+    /// Balance sum behind the tip's state root; see `state_root`.
+    pub balances: Vec<u8>,
+}
+
+/// Half-open range `[start, end)` of event indices.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct EventRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Block {
+    pub fn height(&self) -> Option<u64> {
+        self.header.as_ref().map(|h| h.height)
+    }
+
+    pub fn timestamp_ns(&self) -> Option<u64> {
+        self.header.as_ref().and_then(|h| h.timestamp_ns)
+    }
+
+    /// Hash of the block before this one; `None` for the first block and for blocks
+    /// recorded before headers existed.
+    pub fn parent_hash(&self) -> Option<&[u8]> {
+        self.header.as_ref().and_then(|h| h.parent_hash.as_deref())
+    }
+}
+
+/// What `apply_block` does when an action in the block returns `Err`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum BlockPolicy {
+    /// Revert the whole block and record nothing.
+    #[default]
+    Atomic,
+    /// Revert only the failed actions; record the block with their `Err` results.
+    SkipFailed,
+    /// Keep the actions before the first failure and record only those.
+    StopAtFirstError,
 }
@@ -1,18 +1,43 @@
 use candid::CandidType;
 use serde::{Deserialize, Serialize};
 
-use super::address::Address;
+use super::{address::Address, block::BlockPolicy, fee::FeeSchedule, token::TokenId};
 
 #[derive(Clone, Debug, Serialize, Deserialize, CandidType)]
 pub enum LedgerEvent {
-    Coinbase { to: Address, amount: u128 },
-    Transfer { from: Address, to: Address, amount: u128 },
+    Coinbase { to: Address, amount: u128, token: Option<TokenId> },
+    Transfer { from: Address, to: Address, amount: u128, token: Option<TokenId> },
+    CreateToken { token: TokenId, symbol: String, decimals: u8, minter: Option<Address> },
+    Approve {
+        owner: Address,
+        spender: Address,
+        amount: u128,
+        expires_at: Option<u64>,
+        token: Option<TokenId>,
+    },
+    TransferFrom {
+        spender: Address,
+        from: Address,
+        to: Address,
+        amount: u128,
+        token: Option<TokenId>,
+    },
+    Burn { from: Address, amount: u128, token: Option<TokenId> },
+    /// `collector` is `None` when the fee was burned.
+    Fee { from: Address, amount: u128, collector: Option<Address> },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, CandidType)]
 pub enum MetaEvent {
     SetChainName { name: String },
     BumpCounter { new_counter: u64 },
+    SetBlockPolicy { policy: BlockPolicy },
+    SetFees { fees: Option<FeeSchedule> },
+    SetMinters { minters: Vec<Address> },
+    InitOwner { owner: Address },
+    ProposeOwner { owner: Address },
+    AcceptOwnership { previous: Option<Address>, owner: Address },
+    RenounceOwnership { previous: Address },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, CandidType)]
@@ -20,3 +45,12 @@ pub enum Event {
     Ledger(LedgerEvent),
     Meta(MetaEvent),
 }
+
+/// An event with the block and action that emitted it.
+#[derive(Clone, Debug, Serialize, Deserialize, CandidType)]
+pub struct EventRecord {
+    pub block_height: u64,
+    /// Index of the action within the block.
+    pub action_index: u32,
+    pub event: Event,
+}
@@ -0,0 +1,31 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use super::address::Address;
+
+/// Ledger actions that can be charged a fee.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum FeeKind {
+    Transfer,
+    Approve,
+    TransferFrom,
+}
+
+/// Fees charged to the paying account of a ledger action.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct FeeSchedule {
+    /// Charged when `per_action` has no entry for the action's kind.
+    pub flat: u128,
+    pub per_action: Vec<(FeeKind, u128)>,
+    /// Credited with every fee; `None` burns them.
+    pub collector: Option<Address>,
+}
+
+impl FeeSchedule {
+    pub fn fee_for(&self, kind: FeeKind) -> u128 {
+        self.per_action
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map_or(self.flat, |(_, fee)| *fee)
+    }
+}
@@ -0,0 +1,20 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// Identifies what a staged layer holds, typically the block it was opened for.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct LayerTag {
+    pub height: u64,
+    pub hash: Option<Vec<u8>>,
+    pub timestamp_ns: Option<u64>,
+}
+
+impl LayerTag {
+    pub fn at_height(height: u64) -> Self {
+        Self {
+            height,
+            hash: None,
+            timestamp_ns: None,
+        }
+    }
+}
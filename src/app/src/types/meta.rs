@@ -1,11 +1,23 @@
 use candid::CandidType;
 use serde::{Deserialize, Serialize};
 
-use super::address::Address;
+use super::{address::Address, block::BlockPolicy, fee::FeeSchedule};
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize, CandidType)]
 pub struct Meta {
     pub chain_name: String,
     pub owner: Option<Address>,
     pub counter: u64,
+    pub block_policy: Option<BlockPolicy>,
+    /// No schedule means ledger actions are free.
+    pub fees: Option<FeeSchedule>,
+    /// Sum of all native balances, kept by the ledger on every mint and burn. `None` on chains
+    /// that predate it.
+    pub total_supply: Option<u128>,
+    /// Addresses besides the owner allowed to mint.
+    pub minters: Option<Vec<Address>>,
+    /// Proposed by the owner; becomes `owner` once it accepts.
+    pub pending_owner: Option<Address>,
+    /// Id the next `CreateToken` gets; `None` until the first one.
+    pub next_token_id: Option<u64>,
 }
@@ -4,3 +4,9 @@ pub mod meta;
 mod storable;
 pub mod actions;
 pub mod block;
+pub mod layer;
+pub mod signed;
+pub mod fee;
+pub mod token;
+pub mod allowance;
+pub mod value;
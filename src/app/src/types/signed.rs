@@ -0,0 +1,38 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use super::actions::Action;
+
+#[derive(Clone, Debug, Eq, PartialEq, CandidType, Serialize, Deserialize)]
+pub enum PublicKey {
+    /// 32-byte compressed Edwards point.
+    Ed25519(Vec<u8>),
+    /// SEC1-encoded point, compressed or uncompressed.
+    Secp256k1(Vec<u8>),
+}
+
+impl PublicKey {
+    pub fn scheme_byte(&self) -> u8 {
+        match self {
+            PublicKey::Ed25519(_) => 0,
+            PublicKey::Secp256k1(_) => 1,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            PublicKey::Ed25519(b) | PublicKey::Secp256k1(b) => b,
+        }
+    }
+}
+
+/// An action authorized by the holder of `public_key`. `signature` covers
+/// `auth::signing_message(chain_id, &action, nonce)`; for secp256k1 it is the 64-byte `r || s`
+/// form over the SHA-256 of that message.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct SignedAction {
+    pub action: Action,
+    pub public_key: PublicKey,
+    pub signature: Vec<u8>,
+    pub nonce: u64,
+}
@@ -3,10 +3,114 @@ use std::borrow::Cow;
 use candid::{decode_one, encode_one};
 use ic_stable_structures::{storable::Bound, Storable};
 
-use super::{block::Block, events::{Event, LedgerEvent, MetaEvent}, meta::Meta};
+use super::{
+    address::Address,
+    allowance::{Allowance, AllowanceKey},
+    block::{Block, ChainTip},
+    events::Event,
+    meta::Meta,
+    token::{AccountKey, TokenId, TokenInfo},
+};
+
+// Raw bytes, so maps keyed by `Address` keep the layout of ones keyed by `Vec<u8>`.
+impl Storable for Address {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Address(bytes.into_owned())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Big-endian so token ids sort numerically in stable maps.
+impl Storable for TokenId {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        TokenId(u64::from_be_bytes(bytes.as_ref().try_into().expect("8-byte token id")))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 8,
+        is_fixed_size: true,
+    };
+}
+
+// Token id first, so one token's balances range contiguously.
+impl Storable for AccountKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.token.0.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.owner.0);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (token, owner) = bytes.split_at(8);
+        AccountKey {
+            token: TokenId(u64::from_be_bytes(token.try_into().expect("8-byte token id"))),
+            owner: Address(owner.to_vec()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// The owner is length-prefixed so the spender needs no delimiter.
+impl Storable for AllowanceKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.token.0.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&(self.owner.0.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.owner.0);
+        bytes.extend_from_slice(&self.spender.0);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (token, rest) = bytes.split_at(8);
+        let (len, rest) = rest.split_at(4);
+        let len = u32::from_be_bytes(len.try_into().expect("4-byte length")) as usize;
+        let (owner, spender) = rest.split_at(len);
+        AllowanceKey {
+            token: TokenId(u64::from_be_bytes(token.try_into().expect("8-byte token id"))),
+            owner: Address(owner.to_vec()),
+            spender: Address(spender.to_vec()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Allowance {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_one(self).expect("candid encode Allowance"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_one(bytes.as_ref()).expect("candid decode Allowance")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for TokenInfo {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_one(self).expect("candid encode TokenInfo"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_one(bytes.as_ref()).expect("candid decode TokenInfo")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
 
 impl Storable for Meta {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(encode_one(self).expect("candid encode Meta"))
     }
 
@@ -17,8 +121,20 @@ impl Storable for Meta {
     const BOUND: Bound = Bound::Unbounded;
 }
 
+impl Storable for ChainTip {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_one(self).expect("candid encode ChainTip"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_one(bytes.as_ref()).expect("candid decode ChainTip")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 impl Storable for Event {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(encode_one(self).expect("candid encode Event"))
     }
 
@@ -30,7 +146,7 @@ impl Storable for Event {
 }
 
 impl Storable for Block {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(encode_one(self).expect("candid encode Block"))
     }
 
@@ -0,0 +1,49 @@
+use std::fmt;
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use super::address::Address;
+
+/// Identifies an asset on the chain. The native token is 0; created tokens count up from 1.
+#[derive(
+    Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, CandidType, Serialize, Deserialize,
+)]
+pub struct TokenId(pub u64);
+
+impl TokenId {
+    pub const NATIVE: TokenId = TokenId(0);
+
+    /// Actions and events leave the token out for the native one.
+    pub fn or_native(token: Option<TokenId>) -> TokenId {
+        token.unwrap_or(TokenId::NATIVE)
+    }
+
+    pub fn is_native(self) -> bool {
+        self == TokenId::NATIVE
+    }
+}
+
+impl fmt::Display for TokenId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "token {}", self.0)
+    }
+}
+
+/// Key of a created token's balance. Native balances stay keyed by `Address` alone.
+#[derive(
+    Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, CandidType, Serialize, Deserialize,
+)]
+pub struct AccountKey {
+    pub token: TokenId,
+    pub owner: Address,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub symbol: String,
+    pub decimals: u8,
+    /// The only address allowed to mint; `None` fixes the supply at zero.
+    pub minter: Option<Address>,
+    pub supply: u128,
+}
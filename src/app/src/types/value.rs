@@ -0,0 +1,359 @@
+//! The ICRC-3 generic `Value` and its representation-independent hash, plus the encoding
+//! of appchain blocks (and `Meta`, for the state root) into it. The hash does not depend
+//! on candid, so every party that holds the same block agrees on its hash.
+
+use candid::{CandidType, Int, Nat};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{
+    actions::{Action, ApplyStatus, LedgerAction, MetaAction},
+    address::Address,
+    block::{Block, BlockPolicy},
+    fee::{FeeKind, FeeSchedule},
+    meta::Meta,
+    signed::{PublicKey, SignedAction},
+    token::TokenId,
+};
+
+pub type Hash = [u8; 32];
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The ICRC-3 hash: numbers hash their (S)LEB128 encoding, arrays the concatenation
+    /// of their element hashes, and maps the sorted concatenation of key/value hash pairs.
+    pub fn hash(&self) -> Hash {
+        let mut h = Sha256::new();
+        match self {
+            Value::Blob(b) => h.update(b),
+            Value::Text(t) => h.update(t.as_bytes()),
+            Value::Nat(n) => {
+                let mut buf = Vec::new();
+                n.encode(&mut buf).expect("writing to a Vec cannot fail");
+                h.update(&buf);
+            }
+            Value::Int(i) => {
+                let mut buf = Vec::new();
+                i.encode(&mut buf).expect("writing to a Vec cannot fail");
+                h.update(&buf);
+            }
+            Value::Array(items) => {
+                for item in items {
+                    h.update(item.hash());
+                }
+            }
+            Value::Map(entries) => {
+                let mut pairs: Vec<(Hash, Hash)> = entries
+                    .iter()
+                    .map(|(k, v)| (Value::Text(k.clone()).hash(), v.hash()))
+                    .collect();
+                pairs.sort();
+                for (k, v) in pairs {
+                    h.update(k);
+                    h.update(v);
+                }
+            }
+        }
+        h.finalize().into()
+    }
+
+    fn text(s: impl Into<String>) -> Self {
+        Value::Text(s.into())
+    }
+
+    fn nat(n: impl Into<Nat>) -> Self {
+        Value::Nat(n.into())
+    }
+}
+
+/// The `btype` of every appchain block. A block holds a batch of actions, so none of the
+/// single-transaction ICRC-1/ICRC-2 block types fit.
+pub const BLOCK_TYPE: &str = "appchain";
+
+// Map entries in the order they are written; `None` fields are left out, as ICRC-3
+// does for absent optional fields.
+#[derive(Default)]
+struct Fields(Vec<(String, Value)>);
+
+impl Fields {
+    fn with(mut self, key: &str, value: Value) -> Self {
+        self.0.push((key.into(), value));
+        self
+    }
+
+    fn with_opt(self, key: &str, value: Option<Value>) -> Self {
+        match value {
+            Some(v) => self.with(key, v),
+            None => self,
+        }
+    }
+
+    fn op(name: &str) -> Self {
+        Fields::default().with("op", Value::text(name))
+    }
+
+    fn build(self) -> Value {
+        Value::Map(self.0)
+    }
+}
+
+impl From<&Address> for Value {
+    fn from(a: &Address) -> Self {
+        Value::Blob(a.0.clone())
+    }
+}
+
+fn token(t: &Option<TokenId>) -> Option<Value> {
+    t.map(|t| Value::nat(t.0))
+}
+
+impl Block {
+    /// This block as an ICRC-3 value; `phash` is the hash of the block before it, absent
+    /// for the first block.
+    pub fn to_value(&self) -> Value {
+        Fields::default()
+            .with("btype", Value::text(BLOCK_TYPE))
+            .with_opt("phash", self.parent_hash().map(|h| Value::Blob(h.to_vec())))
+            .with_opt("ts", self.timestamp_ns().map(Value::nat))
+            .with_opt("height", self.height().map(Value::nat))
+            .with_opt(
+                "actions_root",
+                self.header
+                    .as_ref()
+                    .map(|h| Value::Blob(h.actions_root.clone())),
+            )
+            .with_opt(
+                "state_root",
+                self.header
+                    .as_ref()
+                    .map(|h| Value::Blob(h.state_root.clone())),
+            )
+            .with_opt(
+                "events",
+                self.header.as_ref().map(|h| {
+                    let r = h.events_range;
+                    Value::Array(vec![Value::nat(r.start), Value::nat(r.end)])
+                }),
+            )
+            .with_opt(
+                "events_per_action",
+                self.header.as_ref().map(|h| {
+                    Value::Array(h.events_per_action.iter().map(|c| Value::nat(*c)).collect())
+                }),
+            )
+            .with_opt("caller", self.caller.as_ref().map(Value::from))
+            .with(
+                "txs",
+                Value::Array(self.actions.iter().map(Value::from).collect()),
+            )
+            .with(
+                "results",
+                Value::Array(self.results.iter().map(Value::from).collect()),
+            )
+            .build()
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.to_value().hash()
+    }
+}
+
+/// The `actions_root` of a header over `actions`.
+pub fn actions_root(actions: &[Action]) -> Hash {
+    Value::Array(actions.iter().map(Value::from).collect()).hash()
+}
+
+impl From<&Action> for Value {
+    fn from(a: &Action) -> Self {
+        match a {
+            Action::Ledger(l) => l.into(),
+            Action::Meta(m) => m.into(),
+            Action::Signed(s) => s.as_ref().into(),
+        }
+    }
+}
+
+impl From<&SignedAction> for Value {
+    fn from(s: &SignedAction) -> Self {
+        let (scheme, key) = match &s.public_key {
+            PublicKey::Ed25519(k) => ("ed25519", k),
+            PublicKey::Secp256k1(k) => ("secp256k1", k),
+        };
+        Fields::op("signed")
+            .with("action", (&s.action).into())
+            .with("scheme", Value::text(scheme))
+            .with("public_key", Value::Blob(key.clone()))
+            .with("signature", Value::Blob(s.signature.clone()))
+            .with("nonce", Value::nat(s.nonce))
+            .build()
+    }
+}
+
+impl From<&LedgerAction> for Value {
+    fn from(a: &LedgerAction) -> Self {
+        match a {
+            LedgerAction::Coinbase {
+                to,
+                amount,
+                token: t,
+            } => Fields::op("mint")
+                .with("to", to.into())
+                .with("amt", Value::nat(*amount))
+                .with_opt("token", token(t)),
+            LedgerAction::Transfer {
+                from,
+                to,
+                amount,
+                token: t,
+            } => Fields::op("xfer")
+                .with("from", from.into())
+                .with("to", to.into())
+                .with("amt", Value::nat(*amount))
+                .with_opt("token", token(t)),
+            LedgerAction::CreateToken {
+                symbol,
+                decimals,
+                minter,
+            } => Fields::op("create_token")
+                .with("symbol", Value::text(symbol))
+                .with("decimals", Value::nat(*decimals))
+                .with_opt("minter", minter.as_ref().map(Value::from)),
+            LedgerAction::Approve {
+                spender,
+                amount,
+                expires_at,
+                token: t,
+            } => Fields::op("approve")
+                .with("spender", spender.into())
+                .with("amt", Value::nat(*amount))
+                .with_opt("expires_at", expires_at.map(Value::nat))
+                .with_opt("token", token(t)),
+            LedgerAction::TransferFrom {
+                spender,
+                from,
+                to,
+                amount,
+                token: t,
+            } => Fields::op("xfer_from")
+                .with("spender", spender.into())
+                .with("from", from.into())
+                .with("to", to.into())
+                .with("amt", Value::nat(*amount))
+                .with_opt("token", token(t)),
+            LedgerAction::Burn {
+                from,
+                amount,
+                token: t,
+            } => Fields::op("burn")
+                .with("from", from.into())
+                .with("amt", Value::nat(*amount))
+                .with_opt("token", token(t)),
+        }
+        .build()
+    }
+}
+
+impl From<&MetaAction> for Value {
+    fn from(a: &MetaAction) -> Self {
+        match a {
+            MetaAction::SetChainName { name } => {
+                Fields::op("set_chain_name").with("name", Value::text(name))
+            }
+            MetaAction::BumpCounter => Fields::op("bump_counter"),
+            MetaAction::SetBlockPolicy { policy } => {
+                Fields::op("set_block_policy").with("policy", policy.into())
+            }
+            MetaAction::SetFees { fees } => {
+                Fields::op("set_fees").with_opt("fees", fees.as_ref().map(Value::from))
+            }
+            MetaAction::SetMinters { minters } => Fields::op("set_minters").with(
+                "minters",
+                Value::Array(minters.iter().map(Value::from).collect()),
+            ),
+            MetaAction::InitOwner { owner } => Fields::op("init_owner").with("owner", owner.into()),
+            MetaAction::ProposeOwner { owner } => {
+                Fields::op("propose_owner").with("owner", owner.into())
+            }
+            MetaAction::AcceptOwnership => Fields::op("accept_ownership"),
+            MetaAction::RenounceOwnership => Fields::op("renounce_ownership"),
+        }
+        .build()
+    }
+}
+
+impl From<&Meta> for Value {
+    fn from(m: &Meta) -> Self {
+        Fields::default()
+            .with("chain_name", Value::text(&m.chain_name))
+            .with_opt("owner", m.owner.as_ref().map(Value::from))
+            .with("counter", Value::nat(m.counter))
+            .with_opt("block_policy", m.block_policy.as_ref().map(Value::from))
+            .with_opt("fees", m.fees.as_ref().map(Value::from))
+            .with_opt("total_supply", m.total_supply.map(Value::nat))
+            .with_opt(
+                "minters",
+                m.minters
+                    .as_ref()
+                    .map(|ms| Value::Array(ms.iter().map(Value::from).collect())),
+            )
+            .with_opt("pending_owner", m.pending_owner.as_ref().map(Value::from))
+            .with_opt("next_token_id", m.next_token_id.map(Value::nat))
+            .build()
+    }
+}
+
+impl From<&BlockPolicy> for Value {
+    fn from(p: &BlockPolicy) -> Self {
+        Value::text(match p {
+            BlockPolicy::Atomic => "atomic",
+            BlockPolicy::SkipFailed => "skip_failed",
+            BlockPolicy::StopAtFirstError => "stop_at_first_error",
+        })
+    }
+}
+
+impl From<&FeeSchedule> for Value {
+    fn from(f: &FeeSchedule) -> Self {
+        let per_action = f
+            .per_action
+            .iter()
+            .map(|(kind, fee)| {
+                let kind = match kind {
+                    FeeKind::Transfer => "transfer",
+                    FeeKind::Approve => "approve",
+                    FeeKind::TransferFrom => "transfer_from",
+                };
+                Value::Array(vec![Value::text(kind), Value::nat(*fee)])
+            })
+            .collect();
+        Fields::default()
+            .with("flat", Value::nat(f.flat))
+            .with("per_action", Value::Array(per_action))
+            .with_opt("collector", f.collector.as_ref().map(Value::from))
+            .build()
+    }
+}
+
+impl From<&ApplyStatus> for Value {
+    fn from(s: &ApplyStatus) -> Self {
+        match s {
+            ApplyStatus::Ok => Fields::default().with("status", Value::text("ok")),
+            ApplyStatus::Pass { reason } => Fields::default()
+                .with("status", Value::text("pass"))
+                .with("reason", Value::text(reason)),
+            ApplyStatus::Err { error } => Fields::default()
+                .with("status", Value::text("err"))
+                .with("error", Value::text(error)),
+        }
+        .build()
+    }
+}
@@ -1,60 +1,200 @@
 use candid::{decode_one, encode_one, encode_args};
-use app::reducer::reduce_in_order;
-use app::store::StoreGeneric;
+use app::{apply, state_root};
+use app::reducer::{ledger, ReducerRegistry};
+use app::store::{Backends, StoreBases, StoreGeneric};
 use app::types::{
-    actions::{Action, ApplyStatus},
     address::Address,
-    block::Block,
-    events::Event,
+    allowance::{Allowance, AllowanceKey},
+    block::{Block, ChainTip},
+    events::{Event, EventRecord},
+    layer::LayerTag,
     meta::Meta,
+    token::{AccountKey, TokenId, TokenInfo},
 };
-use staging_memory::traits::{CellStore, LogStore, MapStore};
+use staging_memory::shared::Shared;
+use staging_memory::traits::{CellStore, LogStore, MapStore, VersionedMapStore};
+use staging_memory::versioned::{PrunePolicy, Pruner};
 use sled::IVec;
+use ic_stable_structures::Storable;
 use ic_agent::{Agent, agent::http_transport::ReqwestTransport};
 use candid::Principal;
 use anyhow::Result;
 use tokio::time::{sleep, Duration};
+use std::borrow::Cow;
 use std::io::Write;
 
-// Disk-backed Map for Address -> u128 using sled
-struct DiskMap {
+// Disk-backed Map using sled; keys and values use their stable-memory encoding.
+struct DiskMap<K, V> {
     tree: sled::Tree,
+    _marker: std::marker::PhantomData<(K, V)>,
 }
 
-impl DiskMap {
+impl<K, V> DiskMap<K, V> {
     fn new(tree: sled::Tree) -> Self {
-        Self { tree }
+        Self { tree, _marker: std::marker::PhantomData }
     }
 }
 
-impl MapStore<Address, u128> for DiskMap {
-    fn get(&self, k: &Address) -> Option<u128> {
-        self.tree.get(&k.0).ok().flatten().map(|ivec| {
-            let bytes: [u8; 16] = ivec.as_ref().try_into().unwrap();
-            u128::from_be_bytes(bytes)
-        })
+impl<K, V> MapStore<K, V> for DiskMap<K, V>
+where
+    K: Storable + Ord + Clone,
+    V: Storable + Clone,
+{
+    fn get(&self, k: &K) -> Option<V> {
+        self.tree
+            .get(k.to_bytes())
+            .ok()
+            .flatten()
+            .map(|ivec| V::from_bytes(Cow::Borrowed(ivec.as_ref())))
     }
 
-    fn put(&mut self, k: Address, v: u128) {
-        let _ = self.tree.insert(k.0, v.to_be_bytes().to_vec());
+    fn put(&mut self, k: K, v: V) {
+        let _ = self.tree.insert(k.to_bytes(), v.to_bytes().as_ref());
         let _ = self.tree.flush();
     }
 
-    fn remove(&mut self, k: &Address) {
-        let _ = self.tree.remove(&k.0);
+    fn remove(&mut self, k: &K) {
+        let _ = self.tree.remove(k.to_bytes());
         let _ = self.tree.flush();
     }
 
-    fn keys(&self) -> Vec<Address> {
+    fn keys(&self) -> Vec<K> {
         self.tree
             .iter()
             .keys()
             .filter_map(|k| k.ok())
-            .map(|ivec| Address(ivec.to_vec()))
+            .map(|ivec| K::from_bytes(Cow::Borrowed(ivec.as_ref())))
             .collect()
     }
 }
 
+// Disk-backed Map for Address -> u128 that also keeps per-height history in a second tree.
+// History keys are len(addr) ++ addr ++ height (all big-endian) so one address ranges contiguously;
+// an empty value marks a removal.
+struct VersionedDiskMap {
+    current: DiskMap<Address, u128>,
+    history: sled::Tree,
+    version: u64,
+    pruner: Pruner<Address>,
+}
+
+impl VersionedDiskMap {
+    fn new(current: sled::Tree, history: sled::Tree, policy: PrunePolicy) -> Self {
+        Self { current: DiskMap::new(current), history, version: 0, pruner: Pruner::new(policy) }
+    }
+
+    fn history_key(k: &Address, height: u64) -> Vec<u8> {
+        let mut key = Vec::with_capacity(4 + k.0.len() + 8);
+        key.extend_from_slice(&(k.0.len() as u32).to_be_bytes());
+        key.extend_from_slice(&k.0);
+        key.extend_from_slice(&height.to_be_bytes());
+        key
+    }
+
+    fn record(&mut self, k: &Address, v: Option<u128>) {
+        self.pruner.record(self.version, k);
+        let bytes = v.map(|v| v.to_be_bytes().to_vec()).unwrap_or_default();
+        let _ = self.history.insert(Self::history_key(k, self.version), bytes);
+        let _ = self.history.flush();
+    }
+
+    // What `prune_before(height)` drops, for `k` alone.
+    fn prune_key_before(&mut self, k: &Address, height: u64) {
+        let below: Vec<(IVec, bool)> = self
+            .history
+            .range(Self::history_key(k, 0)..Self::history_key(k, height))
+            .filter_map(|r| r.ok())
+            .map(|(key, value)| (key, value.is_empty()))
+            .collect();
+        let Some((newest, removed)) = below.last().cloned() else {
+            return;
+        };
+        for (key, _) in below {
+            if key != newest || removed {
+                let _ = self.history.remove(key);
+            }
+        }
+    }
+}
+
+impl MapStore<Address, u128> for VersionedDiskMap {
+    fn get(&self, k: &Address) -> Option<u128> {
+        self.current.get(k)
+    }
+
+    fn put(&mut self, k: Address, v: u128) {
+        self.record(&k, Some(v));
+        self.current.put(k, v);
+    }
+
+    fn remove(&mut self, k: &Address) {
+        self.record(k, None);
+        self.current.remove(k);
+    }
+
+    fn keys(&self) -> Vec<Address> {
+        self.current.keys()
+    }
+
+    fn clear(&mut self) {
+        self.current.clear();
+        let _ = self.history.clear();
+        let _ = self.history.flush();
+        self.pruner.clear();
+        self.version = 0;
+    }
+
+    fn set_version(&mut self, height: u64) {
+        self.version = height;
+        if let Some((cutoff, keys)) = self.pruner.due(height) {
+            for k in keys {
+                self.prune_key_before(&k, cutoff);
+            }
+            let _ = self.history.flush();
+        }
+    }
+}
+
+impl VersionedMapStore<Address, u128> for VersionedDiskMap {
+    fn get_at(&self, k: &Address, height: u64) -> Option<u128> {
+        self.history
+            .range(Self::history_key(k, 0)..=Self::history_key(k, height))
+            .next_back()
+            .and_then(|r| r.ok())
+            .and_then(|(_, ivec)| {
+                let bytes: [u8; 16] = ivec.as_ref().try_into().ok()?;
+                Some(u128::from_be_bytes(bytes))
+            })
+    }
+
+    fn prune_before(&mut self, height: u64) {
+        // Per address, only the newest entry below `height` is still needed,
+        // and not even that one if it is a removal.
+        let mut drop: Vec<IVec> = Vec::new();
+        let mut last: Option<(IVec, bool)> = None;
+        for (key, value) in self.history.iter().filter_map(|r| r.ok()) {
+            let (addr, h) = key.split_at(key.len() - 8);
+            if u64::from_be_bytes(h.try_into().unwrap()) >= height {
+                continue;
+            }
+            if let Some((prev, removed)) = last.take() {
+                if &prev[..prev.len() - 8] == addr || removed {
+                    drop.push(prev);
+                }
+            }
+            last = Some((key.clone(), value.is_empty()));
+        }
+        if let Some((prev, true)) = last {
+            drop.push(prev);
+        }
+        for k in drop {
+            let _ = self.history.remove(k);
+        }
+        let _ = self.history.flush();
+        self.pruner.forget_before(height);
+    }
+}
+
 // Disk-backed Cell for Meta using sled
 struct DiskCell<T> {
     tree: sled::Tree,
@@ -134,6 +274,19 @@ where
         self.tree.get(k).ok().flatten().map(|ivec| decode_one(ivec.as_ref()).expect("decode log item"))
     }
 
+    fn get_range(&self, start: usize, end: usize) -> Vec<T> {
+        let end = end.min(self.len());
+        if start >= end {
+            return Vec::new();
+        }
+        self.tree
+            .range(Self::idx_key(start as u64)..Self::idx_key(end as u64))
+            .values()
+            .filter_map(|v| v.ok())
+            .map(|ivec| decode_one(ivec.as_ref()).expect("decode log item"))
+            .collect()
+    }
+
     fn append(&mut self, v: T) {
         let idx = self.read_len();
         let _ = self.tree.insert(Self::idx_key(idx), encode_one(&v).expect("encode log item"));
@@ -200,6 +353,19 @@ impl LogStore<Vec<u8>> for DiskBytesLog {
         self.tree.get(k).ok().flatten().map(|ivec| ivec.to_vec())
     }
 
+    fn get_range(&self, start: usize, end: usize) -> Vec<Vec<u8>> {
+        let end = end.min(self.len());
+        if start >= end {
+            return Vec::new();
+        }
+        self.tree
+            .range(Self::idx_key(start as u64)..Self::idx_key(end as u64))
+            .values()
+            .filter_map(|v| v.ok())
+            .map(|ivec| ivec.to_vec())
+            .collect()
+    }
+
     fn append(&mut self, v: Vec<u8>) {
         let idx = self.read_len();
         let _ = self.tree.insert(Self::idx_key(idx), v);
@@ -225,50 +391,48 @@ impl LogStore<Vec<u8>> for DiskBytesLog {
     }
 }
 
-type ClientStore = StoreGeneric<DiskMap, DiskCell<Meta>, DiskLog<Event>, DiskBytesLog>;
+struct DiskBackends;
+
+impl Backends for DiskBackends {
+    type Accounts = VersionedDiskMap;
+    type Nonces = DiskMap<Address, u64>;
+    type TokenAccounts = DiskMap<AccountKey, u128>;
+    type Tokens = DiskMap<TokenId, TokenInfo>;
+    type Allowances = DiskMap<AllowanceKey, Allowance>;
+    type Meta = DiskCell<Meta>;
+    type Events = DiskLog<Event>;
+    type Blocks = DiskBytesLog;
+    type Tip = DiskCell<ChainTip>;
+}
+
+type ClientStore = StoreGeneric<DiskBackends>;
 
 fn default_client_store(db: &sled::Db) -> ClientStore {
-    let accounts = DiskMap::new(db.open_tree("accounts").expect("open accounts"));
+    let accounts = VersionedDiskMap::new(
+        db.open_tree("accounts").expect("open accounts"),
+        db.open_tree("accounts_history").expect("open accounts history"),
+        PrunePolicy::KeepAll,
+    );
+    let nonces = DiskMap::new(db.open_tree("nonces").expect("open nonces"));
+    let token_accounts = DiskMap::new(db.open_tree("token_accounts").expect("open token accounts"));
+    let tokens = DiskMap::new(db.open_tree("tokens").expect("open tokens"));
+    let allowances = DiskMap::new(db.open_tree("allowances").expect("open allowances"));
     let meta = DiskCell::new(db.open_tree("meta").expect("open meta"));
     let events = DiskLog::new(db.open_tree("events").expect("open events"));
     let blocks = DiskBytesLog::new(db.open_tree("blocks").expect("open blocks"));
-
-    StoreGeneric::new(accounts, meta, events, blocks)
-}
-
-fn apply_block_local(store: &mut ClientStore, actions: Vec<Action>) -> Vec<ApplyStatus> {
-    store.push_layer();
-    let mut res = Vec::with_capacity(actions.len());
-    let mut any_err = false;
-    for a in actions.iter() {
-        let mut saw_ok = false;
-        let mut err: Option<String> = None;
-        for reducer in [reduce_in_order] {
-            match reducer(store, a) {
-                ApplyStatus::Ok => saw_ok = true,
-                ApplyStatus::Pass { .. } => {}
-                ApplyStatus::Err { error } => err = Some(error),
-            }
-        }
-        let status = if let Some(e) = err {
-            any_err = true;
-            ApplyStatus::Err { error: e }
-        } else if saw_ok {
-            ApplyStatus::Ok
-        } else {
-            ApplyStatus::Pass { reason: "no reducer handled action".into() }
-        };
-        res.push(status);
-    }
-    if any_err {
-        store.revert_top();
-    } else {
-        let blk = Block { actions: actions.clone(), results: res.clone() };
-        let bytes = encode_one(&blk).expect("encode block");
-        store.blocks.append(bytes);
-        store.commit_top();
-    }
-    res
+    let tip = DiskCell::new(db.open_tree("tip").expect("open tip"));
+
+    StoreGeneric::new(StoreBases {
+        accounts,
+        nonces,
+        token_accounts,
+        tokens,
+        allowances,
+        meta,
+        events,
+        blocks,
+        tip,
+    })
 }
 
 #[derive(candid::CandidType, serde::Deserialize, serde::Serialize)]
@@ -285,6 +449,17 @@ async fn fetch_blocks(agent: &Agent, canister: Principal, start: u64, len: u64)
     Ok(page)
 }
 
+fn parse_address(hex: &str) -> Result<Address> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        anyhow::bail!("address must be an even number of hex digits");
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()?;
+    Ok(Address(bytes))
+}
+
 fn get_canister_id() -> Result<Principal> {
     if let Ok(id) = std::env::var("CANISTER_ID_APPCHAIN") {
         Ok(Principal::from_text(id)?)
@@ -338,33 +513,63 @@ async fn main() -> Result<()> {
     let _ = dotenvy::dotenv();
     let db = sled::open("client_db").expect("open sled");
     let mut store = default_client_store(&db);
+
+    // `client balance-at <address hex> <height>` answers from the local database and exits.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let [cmd, owner, height] = args.as_slice() {
+        if cmd == "balance-at" {
+            let owner = parse_address(owner)?;
+            let height: u64 = height.parse()?;
+            match ledger::balance_at(&store, &owner, height) {
+                Some(balance) => println!("{owner} after block {height}: {balance}"),
+                None => println!("block {height} is not synced locally yet"),
+            }
+            return Ok(());
+        }
+    }
     println!("client initialized; existing blocks(local): {}", store.blocks.len());
 
     let canister = get_canister_id()?;
+    // Verify signed actions as the canister does, against its id.
+    let reducers = ReducerRegistry::<DiskBackends>::with_defaults(canister.as_slice());
     let (agent, url) = build_agent().await?;
     println!("Connected to replica at {url}");
 
     // Determine next from local persisted blocks; also rebuild local state from local blocks
     let local_blocks = store.blocks.len() as u64;
     if local_blocks > 0 {
+        apply::verify_chain(&store, 0..local_blocks)
+            .map_err(|e| anyhow::anyhow!("local block log is broken: {e}"))?;
         println!("Replaying {} local blocks to rebuild state...", local_blocks);
         store.clear_state_preserve_blocks();
-        store.push_layer();
-        for i in 0..local_blocks {
-            if let Some(bytes) = store.blocks.get(i as usize) {
-                let blk: Block = decode_one(bytes.as_slice()).expect("decode local block");
-                for action in blk.actions.iter() {
-                    let _ = reduce_in_order(&mut store, action);
-                }
-            }
+        let blocks: Vec<Block> = store
+            .blocks
+            .iter()
+            .map(|bytes| decode_one(bytes.as_slice()).expect("decode local block"))
+            .collect();
+        for (i, blk) in blocks.iter().enumerate() {
+            store.push_tagged_layer(LayerTag::at_height(i as u64));
+            apply::replay_block(&reducers, &mut store, blk)
+                .map_err(|e| anyhow::anyhow!("local replay diverged at block {i}: {e}"))?;
+            // Committed before the next block so reads never walk a stack of replayed layers.
+            store.commit_all();
         }
-        store.commit_top();
+        ledger::verify_supply(&store)
+            .map_err(|e| anyhow::anyhow!("local replay broke the supply invariant: {e}"))?;
+        state_root::check_tip(&store)
+            .map_err(|e| anyhow::anyhow!("local state does not match the chain tip: {e}"))?;
         let counter = store.meta.get().map(|m| m.counter).unwrap_or(0);
         println!("Local state rebuilt. events_local={} counter_local={}", store.events.len(), counter);
     }
-    let mut next: u64 = local_blocks;
+    // From here on the sync loop is the only writer; readers go through snapshots.
+    let store = Shared::new(store);
+    tokio::spawn(report_status(store.clone()));
 
     loop {
+        let (version, next) = {
+            let snap = store.snapshot();
+            (snap.version(), snap.blocks.len() as u64)
+        };
         match fetch_blocks(&agent, canister, next, 1000).await {
             Ok(page) => {
                 let count = page.blocks.len() as u64;
@@ -374,26 +579,50 @@ async fn main() -> Result<()> {
                     continue;
                 }
 
-                store.push_layer();
+                // One write transaction per block, so readers get in between blocks.
+                let mut version = version;
                 for blk in page.blocks.iter() {
-                    for action in blk.actions.iter() {
-                        let _ = reduce_in_order(&mut store, action);
-                    }
-                    let bytes = encode_one(blk).expect("encode block");
-                    store.blocks.append(bytes);
+                    let mut txn = match store.begin(version) {
+                        Ok(txn) => txn,
+                        Err(conflict) => {
+                            anyhow::bail!("{conflict}: the sync loop is the only writer");
+                        }
+                    };
+                    let s: &mut ClientStore = &mut txn;
+                    let height = s.blocks.len() as u64;
+                    // Tagged with its height so the versioned base records it there.
+                    s.tag_top(LayerTag::at_height(height));
+                    // A block that doesn't fit the local chain, or leaves us with different
+                    // state than the canister, is a hard error; stop rather than sync past it.
+                    let state = apply::replay_verified_block(&reducers, s, blk)
+                        .map_err(|e| anyhow::anyhow!("rejected remote block: {e}"))?;
+                    apply::append_block(s, blk, &state);
+                    version = txn.commit();
+                    // Only committed layers go to disk.
+                    store.maintain(|s| s.commit_all());
                 }
-                store.commit_top();
 
-                next += count;
-                let counter = store.meta.get().map(|m| m.counter).unwrap_or(0);
+                let (counter, events_local) = {
+                    let snap = store.snapshot();
+                    (snap.meta.get().map(|m| m.counter).unwrap_or(0), snap.events.len())
+                };
+
                 println!(
                     "\nApplied {} blocks; next={} total_remote={} events_local={} counter_local={}",
                     count,
-                    next,
+                    next + count,
                     page.total,
-                    store.events.len(),
+                    events_local,
                     counter
                 );
+                for height in next..next + count {
+                    for record in block_events(&store, height).unwrap_or_default() {
+                        println!(
+                            "  block {height} action {}: {:?}",
+                            record.action_index, record.event
+                        );
+                    }
+                }
             }
             Err(err) => {
                 eprintln!("fetch error at start={next}: {err:?}");
@@ -402,3 +631,98 @@ async fn main() -> Result<()> {
         }
     }
 }
+
+/// The events block `height` emitted, read from a snapshot between blocks; the client's
+/// counterpart to the canister's `get_block_events`.
+fn block_events(store: &Shared<ClientStore>, height: u64) -> Option<Vec<EventRecord>> {
+    apply::block_events(&store.snapshot(), height)
+}
+
+// Reads committed state from snapshots while the sync loop writes; never sees a partial block.
+async fn report_status(store: Shared<ClientStore>) {
+    let mut seen = 0;
+    loop {
+        sleep(Duration::from_secs(10)).await;
+        let line = {
+            let snap = store.snapshot();
+            if snap.version() == seen {
+                continue;
+            }
+            seen = snap.version();
+            // Only cheap reads here: the sync loop waits for this snapshot to be dropped.
+            format!(
+                "[v{}] blocks_local={} events_local={}",
+                seen,
+                snap.blocks.len(),
+                snap.events.len()
+            )
+        };
+        println!("{line}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(b: u8) -> Address {
+        Address(vec![b; 29])
+    }
+
+    fn disk_map(policy: PrunePolicy) -> VersionedDiskMap {
+        let db = sled::Config::new().temporary(true).open().expect("open temporary sled");
+        VersionedDiskMap::new(
+            db.open_tree("accounts").expect("open accounts"),
+            db.open_tree("accounts_history").expect("open accounts history"),
+            policy,
+        )
+    }
+
+    fn write_at(m: &mut VersionedDiskMap, height: u64, k: &Address, v: Option<u128>) {
+        m.set_version(height);
+        match v {
+            Some(v) => m.put(k.clone(), v),
+            None => m.remove(k),
+        }
+    }
+
+    #[test]
+    fn parse_address_reads_what_display_writes() {
+        assert_eq!(parse_address(&addr(0xab).to_string()).unwrap(), addr(0xab));
+        assert!(parse_address("abc").is_err());
+        assert!(parse_address("zz").is_err());
+    }
+
+    #[test]
+    fn disk_prune_keeps_newest_value_and_drops_removals() {
+        let mut m = disk_map(PrunePolicy::KeepAll);
+        write_at(&mut m, 1, &addr(1), Some(1));
+        write_at(&mut m, 2, &addr(1), Some(2));
+        write_at(&mut m, 5, &addr(1), Some(5));
+        write_at(&mut m, 1, &addr(2), Some(7));
+        write_at(&mut m, 2, &addr(2), None);
+        m.prune_before(4);
+        assert_eq!(m.history.len(), 2);
+        assert_eq!(m.get_at(&addr(1), 4), Some(2));
+        assert_eq!(m.get_at(&addr(1), 5), Some(5));
+        assert_eq!(m.get_at(&addr(2), 1), None);
+        assert_eq!(m.get_at(&addr(2), 4), None);
+    }
+
+    #[test]
+    fn disk_keep_last_prunes_like_a_full_prune() {
+        let mut kept = disk_map(PrunePolicy::KeepLast(2));
+        let mut full = disk_map(PrunePolicy::KeepAll);
+        for height in 1..=12u64 {
+            for m in [&mut kept, &mut full] {
+                let v = (height % 3 != 0).then_some(height as u128);
+                write_at(m, height, &addr(height as u8 % 2), v);
+            }
+            full.prune_before(height.saturating_sub(2));
+            let keys = |m: &VersionedDiskMap| -> Vec<IVec> {
+                m.history.iter().keys().filter_map(|k| k.ok()).collect()
+            };
+            assert_eq!(keys(&kept), keys(&full), "at height {height}");
+        }
+    }
+}
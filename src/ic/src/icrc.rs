@@ -0,0 +1,158 @@
+//! ICRC-1 and ICRC-3 types, and the mapping between ICRC accounts and appchain addresses.
+
+use app::types::{
+    address::Address,
+    value::{Hash, Value},
+};
+use candid::{CandidType, Nat, Principal};
+use ic_certification::{empty, fork, label, leaf};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub type Subaccount = [u8; 32];
+
+pub type HashTree = ic_certification::HashTree;
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    /// Principal bytes for the default subaccount, principal bytes ++ subaccount otherwise,
+    /// so a principal's default account is the address `apply_block` callers act as.
+    pub fn address(&self) -> Address {
+        let owner = Address::from(self.owner.as_slice().to_vec());
+        Address::with_subaccount(&owner, self.subaccount.as_ref())
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+/// How long a transfer that sets `created_at_time` is remembered for deduplication, and
+/// how far ahead of the ledger's clock that time may be.
+pub const TX_WINDOW_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+pub const PERMITTED_DRIFT_NS: u64 = 2 * 60 * 1_000_000_000;
+
+impl TransferArg {
+    /// Identifies the transfer `caller` asked for, for deduplication: SHA-256 of the
+    /// caller and the candid-encoded argument.
+    pub fn tx_hash(&self, caller: &Principal) -> [u8; 32] {
+        let bytes = candid::encode_args((caller, self)).expect("encode transfer");
+        Sha256::digest(bytes).into()
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(candid::Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StandardRecord {
+    pub name: String,
+    pub url: String,
+}
+
+/// The native token's ICRC-1 symbol and decimals; the chain name serves as its name.
+pub const NATIVE_SYMBOL: &str = "APP";
+pub const NATIVE_DECIMALS: u8 = 8;
+
+/// Converts an ICRC amount to a ledger amount; `None` if it does not fit in a `u128`.
+pub fn to_amount(n: &Nat) -> Option<u128> {
+    u128::try_from(n.0.clone()).ok()
+}
+
+/// Converts an ICRC block index or length, saturating at `u64::MAX`.
+pub fn to_index(n: &Nat) -> u64 {
+    u64::try_from(n.0.clone()).unwrap_or(u64::MAX)
+}
+
+impl TransferError {
+    pub fn generic(message: impl Into<String>) -> Self {
+        TransferError::GenericError {
+            error_code: Nat::from(0u32),
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+candid::define_function!(pub GetBlocksFn : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: GetBlocksFn,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    /// Always empty: the whole log lives in this canister.
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DataCertificate {
+    pub certificate: Vec<u8>,
+    pub hash_tree: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String,
+}
+
+/// The certified tip of the block log, as ICRC-3 lays it out: `last_block_index` as
+/// LEB128 and `last_block_hash`. `None` while the log is empty.
+pub fn tip_tree(tip: Option<(u64, Hash)>) -> HashTree {
+    let Some((index, hash)) = tip else {
+        return empty();
+    };
+    let mut leb = Vec::new();
+    Nat::from(index)
+        .encode(&mut leb)
+        .expect("writing to a Vec cannot fail");
+    fork(
+        label(b"last_block_hash".to_vec(), leaf(hash.to_vec())),
+        label(b"last_block_index".to_vec(), leaf(leb)),
+    )
+}
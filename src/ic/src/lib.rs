@@ -1,64 +1,112 @@
-use app::reducer::reduce_in_order;
-use app::store::StoreGeneric;
+use app::{apply, state_root};
+use app::reducer::{ledger, ActionContext, ReducerRegistry};
+use app::store::{StagedLayers, StoreGeneric};
 use app::types::{
     actions::{Action, ApplyStatus, LedgerAction, MetaAction},
     address::Address,
-    block::Block,
-    events::Event,
-    meta::Meta,
+    allowance::{Allowance, AllowanceKey},
+    block::{Block, BlockPolicy},
+    events::{Event, EventRecord},
+    fee::{FeeKind, FeeSchedule},
+    layer::LayerTag,
+    token::{TokenId, TokenInfo},
+    value::{Hash, BLOCK_TYPE},
 };
 use std::cell::RefCell;
-use candid::CandidType;
+use candid::{CandidType, Nat, Principal};
 use serde::{Deserialize, Serialize};
 
+pub mod icrc;
 pub mod stable_backend;
 
-type Store = StoreGeneric<
-    stable_backend::StableMapBackend,
-    stable_backend::StableCellBackend,
-    stable_backend::StableLogBackend<Event>,
-    stable_backend::StableLogBackend<Vec<u8>>,
->;
+type Store = StoreGeneric<stable_backend::StableBackends>;
+
+const STAGED_LAYERS_MEMORY_ID: u8 = 6;
+const RECENT_TRANSFERS_MEMORY_ID: u8 = 14;
 
 fn default_store() -> Store {
-    let (accounts, meta, events, blocks) = stable_backend::make_stable_backends();
-    StoreGeneric::new(accounts, meta, events, blocks)
+    StoreGeneric::new(stable_backend::make_stable_backends())
 }
 
 thread_local! {
     static STORE: RefCell<Store> = RefCell::new(default_store());
+    static RECENT_TRANSFERS: RefCell<stable_backend::RecentTransfers> =
+        RefCell::new(stable_backend::RecentTransfers::from_id(RECENT_TRANSFERS_MEMORY_ID));
+    // Signed actions are bound to this canister's id.
+    static REDUCERS: ReducerRegistry<stable_backend::StableBackends> =
+        ReducerRegistry::with_defaults(ic_cdk::api::id().as_slice());
 }
 
 fn with_store_mut<F, R>(f: F) -> R
 where
     F: FnOnce(&mut Store) -> R,
 {
-    STORE.with(|s| f(&mut *s.borrow_mut()))
+    STORE.with(|s| f(&mut s.borrow_mut()))
 }
 
-#[ic_cdk::update]
+fn with_reducers_and_store_mut<F, R>(f: F) -> R
+where
+    F: FnOnce(&ReducerRegistry<stable_backend::StableBackends>, &mut Store) -> R,
+{
+    REDUCERS.with(|r| with_store_mut(|s| f(r, s)))
+}
+
+fn caller_is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("caller is not a controller".into())
+    }
+}
+
+/// The caller's principal bytes as an address; `None` for the anonymous principal,
+/// which must never match an owner or minter.
+fn caller_address() -> Option<Address> {
+    let caller = ic_cdk::caller();
+    (caller != Principal::anonymous()).then(|| Address::from(caller.as_slice().to_vec()))
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
 fn txn_push_layer() {
     with_store_mut(|s| s.push_layer());
 }
 
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "caller_is_controller")]
 fn txn_commit_top() {
     with_store_mut(|s| s.commit_top());
 }
 
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "caller_is_controller")]
 fn txn_commit_all() {
     with_store_mut(|s| s.commit_all());
 }
 
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "caller_is_controller")]
 fn txn_commit_oldest() {
     with_store_mut(|s| s.commit_oldest());
 }
 
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "caller_is_controller")]
 fn txn_revert_top() {
     with_store_mut(|s| s.revert_top());
+    certify_tip();
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn txn_commit_through(tag: LayerTag) -> bool {
+    with_store_mut(|s| s.commit_through(&tag))
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn txn_revert_to(tag: LayerTag) -> bool {
+    let reverted = with_store_mut(|s| s.revert_to(&tag));
+    certify_tip();
+    reverted
+}
+
+#[ic_cdk::query]
+fn txn_layer_tags() -> Vec<Option<LayerTag>> {
+    STORE.with(|s| s.borrow().layer_tags().to_vec())
 }
 
 #[ic_cdk::query]
@@ -69,6 +117,49 @@ fn get_balance(addr: Vec<u8>) -> u128 {
     })
 }
 
+/// `addr`'s native balance right after block `height`; `None` until that block is
+/// committed to stable memory.
+#[ic_cdk::query]
+fn get_balance_at(addr: Vec<u8>, height: u64) -> Option<u128> {
+    STORE.with(|s| ledger::balance_at(&s.borrow(), &Address::from(addr), height))
+}
+
+#[ic_cdk::query]
+fn get_token_balance(token: TokenId, addr: Vec<u8>) -> u128 {
+    STORE.with(|s| ledger::balance(&s.borrow(), token, &Address::from(addr)))
+}
+
+/// What `spender` may currently move out of `owner`'s native balance, as in ICRC-2.
+/// Expired allowances read as zero.
+#[ic_cdk::query]
+fn allowance(owner: Vec<u8>, spender: Vec<u8>) -> Allowance {
+    let key = AllowanceKey {
+        token: TokenId::NATIVE,
+        owner: Address::from(owner),
+        spender: Address::from(spender),
+    };
+    STORE.with(|s| s.borrow().allowances.get(&key))
+        .filter(|a| a.is_live(Some(ic_cdk::api::time())))
+        .unwrap_or_default()
+}
+
+#[ic_cdk::query]
+fn get_token(token: TokenId) -> Option<TokenInfo> {
+    STORE.with(|s| s.borrow().tokens.get(&token))
+}
+
+/// Native tokens in existence; created tokens report theirs in `get_token`.
+#[ic_cdk::query]
+fn total_supply() -> u128 {
+    STORE.with(|s| ledger::total_supply(&s.borrow()))
+}
+
+/// The nonce the next signed action from `addr` must carry.
+#[ic_cdk::query]
+fn get_nonce(addr: Vec<u8>) -> u64 {
+    STORE.with(|s| s.borrow().nonces.get(&Address::from(addr)).unwrap_or(0))
+}
+
 #[ic_cdk::query]
 fn events_len() -> usize {
     STORE.with(|s| s.borrow().events.len())
@@ -79,6 +170,12 @@ fn get_event(i: usize) -> Option<Event> {
     STORE.with(|s| s.borrow().events.get(i))
 }
 
+/// The events block `height` emitted, with the action index of each.
+#[ic_cdk::query]
+fn get_block_events(height: u64) -> Option<Vec<EventRecord>> {
+    STORE.with(|s| apply::block_events(&s.borrow(), height))
+}
+
 
 
 #[ic_cdk::query]
@@ -92,66 +189,367 @@ fn meta_get_counter() -> u64 {
     STORE.with(|s| s.borrow().meta.get().map(|m| m.counter).unwrap_or(0))
 }
 
+#[ic_cdk::query]
+fn meta_get_block_policy() -> BlockPolicy {
+    STORE.with(|s| s.borrow().meta.get().and_then(|m| m.block_policy).unwrap_or_default())
+}
+
+#[ic_cdk::query]
+fn meta_get_owner() -> Option<Vec<u8>> {
+    STORE.with(|s| s.borrow().meta.get().and_then(|m| m.owner).map(|a| a.0))
+}
+
+#[ic_cdk::query]
+fn meta_get_pending_owner() -> Option<Vec<u8>> {
+    STORE.with(|s| s.borrow().meta.get().and_then(|m| m.pending_owner).map(|a| a.0))
+}
+
+#[ic_cdk::query]
+fn meta_get_fees() -> Option<FeeSchedule> {
+    STORE.with(|s| s.borrow().meta.get().and_then(|m| m.fees))
+}
+
 #[ic_cdk::update]
 fn apply_block(actions: Vec<Action>) -> Vec<ApplyStatus> {
-    with_store_mut(|s| {
-        s.push_layer();
-        let mut res = Vec::with_capacity(actions.len());
-        let mut any_err = false;
-        for a in actions.iter() {
-            let status = reduce_in_order(s, a);
-            if let ApplyStatus::Err { .. } = status {
-                any_err = true;
+    if actions.iter().any(is_init_owner) {
+        ic_cdk::trap("InitOwner is only accepted at install or upgrade");
+    }
+    submit_block(caller_address(), actions).statuses
+}
+
+fn is_init_owner(action: &Action) -> bool {
+    match action {
+        Action::Meta(MetaAction::InitOwner { .. }) => true,
+        Action::Signed(signed) => is_init_owner(&signed.action),
+        _ => false,
+    }
+}
+
+fn submit_block(caller: Option<Address>, actions: Vec<Action>) -> apply::BlockOutcome {
+    let outcome = with_reducers_and_store_mut(|reducers, s| {
+        // Inside an operator session the block stays staged in its own tagged layer;
+        // otherwise it is written through to stable memory.
+        let speculative = s.layer_count() > 1;
+        let ctx = ActionContext::for_block(caller, Some(ic_cdk::api::time()));
+        let outcome = apply::apply_block(reducers, s, ctx, actions);
+        if outcome.height.is_some() && !speculative {
+            s.commit_all();
+        }
+        outcome
+    });
+    certify_tip();
+    outcome
+}
+
+#[ic_cdk::query]
+fn icrc1_name() -> String {
+    STORE.with(|s| s.borrow().meta.get().map(|m| m.chain_name).unwrap_or_default())
+}
+
+#[ic_cdk::query]
+fn icrc1_symbol() -> String {
+    icrc::NATIVE_SYMBOL.into()
+}
+
+#[ic_cdk::query]
+fn icrc1_decimals() -> u8 {
+    icrc::NATIVE_DECIMALS
+}
+
+/// The transfer fee in effect, in the native token.
+#[ic_cdk::query]
+fn icrc1_fee() -> Nat {
+    STORE.with(|s| native_transfer_fee(&s.borrow())).into()
+}
+
+fn native_transfer_fee(store: &Store) -> u128 {
+    store
+        .meta
+        .get()
+        .and_then(|m| m.fees)
+        .map_or(0, |f| f.fee_for(FeeKind::Transfer))
+}
+
+#[ic_cdk::query]
+fn icrc1_metadata() -> Vec<(String, icrc::MetadataValue)> {
+    use icrc::MetadataValue;
+    vec![
+        ("icrc1:name".into(), MetadataValue::Text(icrc1_name())),
+        ("icrc1:symbol".into(), MetadataValue::Text(icrc1_symbol())),
+        ("icrc1:decimals".into(), MetadataValue::Nat(icrc1_decimals().into())),
+        ("icrc1:fee".into(), MetadataValue::Nat(icrc1_fee())),
+    ]
+}
+
+#[ic_cdk::query]
+fn icrc1_total_supply() -> Nat {
+    STORE.with(|s| ledger::total_supply(&s.borrow())).into()
+}
+
+/// Minting goes through `Coinbase` by the owner or a minter, not transfers from a
+/// designated account.
+#[ic_cdk::query]
+fn icrc1_minting_account() -> Option<icrc::Account> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc1_balance_of(account: icrc::Account) -> Nat {
+    STORE.with(|s| s.borrow().accounts.get(&account.address()).unwrap_or(0)).into()
+}
+
+#[ic_cdk::query]
+fn icrc1_supported_standards() -> Vec<icrc::StandardRecord> {
+    vec![
+        icrc::StandardRecord {
+            name: "ICRC-1".into(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1".into(),
+        },
+        icrc::StandardRecord {
+            name: "ICRC-3".into(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3".into(),
+        },
+    ]
+}
+
+/// A native `Transfer` out of the caller's account, recorded as a block of its own.
+/// Transfers that set `created_at_time` are deduplicated over `icrc::TX_WINDOW_NS`; the
+/// memo only tells otherwise identical transfers apart and is not stored.
+#[ic_cdk::update]
+fn icrc1_transfer(arg: icrc::TransferArg) -> Result<Nat, icrc::TransferError> {
+    use icrc::TransferError;
+    let amount =
+        icrc::to_amount(&arg.amount).ok_or_else(|| TransferError::generic("amount too large"))?;
+    let expected_fee = STORE.with(|s| native_transfer_fee(&s.borrow()));
+    if arg.fee.as_ref().is_some_and(|f| icrc::to_amount(f) != Some(expected_fee)) {
+        return Err(TransferError::BadFee {
+            expected_fee: expected_fee.into(),
+        });
+    }
+    let now = ic_cdk::api::time();
+    let dedup = match arg.created_at_time {
+        Some(created) => {
+            if created.saturating_add(icrc::TX_WINDOW_NS + icrc::PERMITTED_DRIFT_NS) < now {
+                return Err(TransferError::TooOld);
+            }
+            if created > now.saturating_add(icrc::PERMITTED_DRIFT_NS) {
+                return Err(TransferError::CreatedInFuture { ledger_time: now });
             }
-            res.push(status);
+            let tx_hash = arg.tx_hash(&ic_cdk::caller());
+            if let Some(height) = RECENT_TRANSFERS.with(|r| r.borrow().get(created, &tx_hash)) {
+                return Err(TransferError::Duplicate {
+                    duplicate_of: height.into(),
+                });
+            }
+            // A block staged by an operator session may still be reverted, and with it
+            // the transfer a retry would be pointed at.
+            if STORE.with(|s| s.borrow().layer_count() > 1) {
+                return Err(TransferError::TemporarilyUnavailable);
+            }
+            Some((created, tx_hash))
         }
-        if any_err {
-            s.revert_top();
-        } else {
-            let blk = Block { actions: actions.clone(), results: res.clone() };
-            let bytes = candid::encode_one(&blk).expect("encode block");
-            s.blocks.append(bytes);
-            s.commit_top();
+        None => None,
+    };
+    let from = icrc::Account {
+        owner: ic_cdk::caller(),
+        subaccount: arg.from_subaccount,
+    }
+    .address();
+    let checked =
+        STORE.with(|s| ledger::check_transfer(&s.borrow(), TokenId::NATIVE, &from, amount));
+    match checked {
+        Ok(()) => {}
+        Err(ledger::Rejection::InsufficientFunds) => {
+            let balance = STORE.with(|s| s.borrow().accounts.get(&from).unwrap_or(0));
+            return Err(TransferError::InsufficientFunds {
+                balance: balance.into(),
+            });
+        }
+        Err(r) => return Err(TransferError::generic(r.to_string())),
+    }
+    let action = Action::Ledger(LedgerAction::Transfer {
+        from,
+        to: arg.to.address(),
+        amount,
+        token: None,
+    });
+    let outcome = submit_block(caller_address(), vec![action]);
+    let height = match (outcome.statuses.into_iter().next(), outcome.height) {
+        (Some(ApplyStatus::Ok), Some(height)) => height,
+        (Some(ApplyStatus::Pass { reason }), _) => return Err(TransferError::generic(reason)),
+        (Some(ApplyStatus::Err { error }), _) => return Err(TransferError::generic(error)),
+        _ => return Err(TransferError::generic("transfer was not recorded")),
+    };
+    if let Some((created, tx_hash)) = dedup {
+        RECENT_TRANSFERS.with(|r| {
+            let mut r = r.borrow_mut();
+            r.insert(created, &tx_hash, height);
+            r.prune_before(now.saturating_sub(icrc::TX_WINDOW_NS + icrc::PERMITTED_DRIFT_NS));
+        });
+    }
+    Ok(height.into())
+}
+
+/// Blocks follow the ICRC-3 generic block schema; the fields of the `appchain` type are
+/// documented in `docs/icrc3-blocks.md`.
+#[ic_cdk::query]
+fn icrc3_supported_block_types() -> Vec<icrc::SupportedBlockType> {
+    vec![icrc::SupportedBlockType {
+        block_type: BLOCK_TYPE.into(),
+        url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3#generic-block-schema"
+            .into(),
+    }]
+}
+
+/// Blocks as ICRC-3 values, in request order. At most `MAX_BLOCKS` are returned per call,
+/// so callers page on `log_length`.
+#[ic_cdk::query]
+fn icrc3_get_blocks(args: Vec<icrc::GetBlocksArgs>) -> icrc::GetBlocksResult {
+    const MAX_BLOCKS: u64 = 1000;
+    STORE.with(|s| {
+        let store = s.borrow();
+        let total = store.blocks.len() as u64;
+        let mut budget = MAX_BLOCKS;
+        let mut blocks = Vec::new();
+        for arg in &args {
+            let start = icrc::to_index(&arg.start).min(total);
+            let len = icrc::to_index(&arg.length).min(budget);
+            let end = start.saturating_add(len).min(total);
+            budget -= end - start;
+            for (id, bytes) in (start..end).zip(store.blocks.range(start as usize..end as usize)) {
+                let block: Block = candid::decode_one(bytes.as_slice()).expect("decode block");
+                blocks.push(icrc::BlockWithId {
+                    id: id.into(),
+                    block: block.to_value(),
+                });
+            }
+        }
+        icrc::GetBlocksResult {
+            log_length: total.into(),
+            blocks,
+            archived_blocks: vec![],
         }
-        res
     })
 }
 
+#[ic_cdk::query]
+fn icrc3_get_tip_certificate() -> Option<icrc::DataCertificate> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let tree = icrc::tip_tree(current_tip());
+    // Self-describing CBOR (tag 55799), like the certificate itself.
+    let mut hash_tree = Vec::new();
+    ciborium::into_writer(&ciborium::tag::Required::<_, 55799>(&tree), &mut hash_tree)
+        .expect("encode hash tree");
+    Some(icrc::DataCertificate {
+        certificate,
+        hash_tree,
+    })
+}
+
+fn current_tip() -> Option<(u64, Hash)> {
+    STORE.with(|s| {
+        let store = s.borrow();
+        let hash = apply::tip_hash(&store)?;
+        Some((store.blocks.len() as u64 - 1, hash))
+    })
+}
+
+// Re-certifies the tip; called by every update that can change the visible block log.
+fn certify_tip() {
+    let tree = icrc::tip_tree(current_tip());
+    ic_cdk::api::set_certified_data(&tree.digest());
+}
+
+#[derive(CandidType, Deserialize)]
+struct InitArgs {
+    owner: Option<Principal>,
+}
+
+// Records the first owner as a block so replay reproduces it. No-op once the chain has one.
+fn init_owner(owner: Principal) {
+    let owned = STORE.with(|s| s.borrow().meta.get().and_then(|m| m.owner).is_some());
+    if owned {
+        return;
+    }
+    let owner = Address::from(owner.as_slice().to_vec());
+    submit_block(
+        caller_address(),
+        vec![Action::Meta(MetaAction::InitOwner { owner })],
+    );
+}
+
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    let owner = args.and_then(|a| a.owner).unwrap_or_else(ic_cdk::caller);
+    init_owner(owner);
+}
+
 #[ic_cdk::pre_upgrade]
 fn pre_upgrade() {
-    with_store_mut(|s| s.commit_all());
+    // Blocks outside an operator session were written through to stable memory by
+    // `submit_block`; only what an open session still stages on the heap needs saving.
+    let layers = STORE.with(|s| s.borrow().export_layers());
+    let bytes = candid::encode_one(&layers).expect("encode staged layers");
+    stable_backend::StagedLayersCell::from_id(STAGED_LAYERS_MEMORY_ID).save(bytes);
 }
 
-#[ic_cdk::update]
+// An upgrade only sets an owner when one is passed explicitly, so a renounced chain
+// is not silently handed back to whoever upgrades it.
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    if let Some(bytes) = stable_backend::StagedLayersCell::from_id(STAGED_LAYERS_MEMORY_ID).take() {
+        let layers: StagedLayers = candid::decode_one(&bytes).expect("decode staged layers");
+        if let Err(e) = with_store_mut(|s| s.restore_layers(layers)) {
+            ic_cdk::trap(&format!("cannot restore staged layers: {e}"));
+        }
+    }
+    certify_tip();
+    if let Some(owner) = args.and_then(|a| a.owner) {
+        init_owner(owner);
+    }
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
 fn clear_all() {
-    with_store_mut(|s| {
-        s.accounts.clear_all();
-        s.meta.clear_all();
-        s.events.clear_all();
-        s.blocks.clear_all();
-    });
+    with_store_mut(|s| s.clear_all());
+    RECENT_TRANSFERS.with(|r| r.borrow_mut().clear());
+    certify_tip();
 }
 
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "caller_is_controller")]
 fn reset_and_replay() {
-    with_store_mut(|s| {
+    with_reducers_and_store_mut(|reducers, s| {
         // Clear current state (accounts/meta/events), keep blocks
         s.clear_state_preserve_blocks();
-        // Re-apply all actions from blocks in a single layer
-        s.push_layer();
+        // Re-apply each block in its own tagged layer and commit it, so the balance
+        // history is recorded by height again. A trap below rolls all of it back.
+        const CHUNK: usize = 256;
         let total = s.blocks.len();
-        for i in 0..total {
-            if let Some(bytes) = s.blocks.get(i) {
+        for start in (0..total).step_by(CHUNK) {
+            for (height, bytes) in (start..).zip(s.blocks.get_range(start, start + CHUNK)) {
                 let block: Block = candid::decode_one(bytes.as_slice()).expect("decode block");
-                for action in block.actions.iter() {
-                    let _ = reduce_in_order(s, action);
+                s.push_tagged_layer(LayerTag::at_height(height as u64));
+                if let Err(e) = apply::replay_block(reducers, s, &block) {
+                    ic_cdk::trap(&format!("replay diverged at block {height}: {e}"));
                 }
+                s.commit_all();
             }
         }
-        s.commit_top();
+        if let Err(e) = ledger::verify_supply(s) {
+            ic_cdk::trap(&format!("replay broke the supply invariant: {e}"));
+        }
+        if let Err(e) = state_root::check_tip(s) {
+            ic_cdk::trap(&format!("replayed state does not match the chain tip: {e}"));
+        }
     });
 }
 
+/// Checks that blocks `start..end` form an unbroken hash chain; see `apply::verify_chain`.
+#[ic_cdk::query]
+fn verify_chain(start: u64, end: u64) -> Result<(), String> {
+    STORE.with(|s| apply::verify_chain(&s.borrow(), start..end)).map_err(|e| e.to_string())
+}
+
 ic_cdk::export_candid!();
 
 #[derive(CandidType, Serialize, Deserialize)]
@@ -171,18 +569,14 @@ fn get_blocks(start: u64, len: u64) -> BlocksPage {
         let end = start.saturating_add(len).min(total);
         let mut blocks: Vec<Block> = Vec::new();
         let mut acc_bytes: usize = 0;
-        for i in start..end {
-            if let Some(bytes) = store.blocks.get(i as usize) {
-                let sz = bytes.len();
-                if acc_bytes + sz > MAX_BYTES && !blocks.is_empty() {
-                    break;
-                }
-                let block: Block = candid::decode_one(bytes.as_slice()).unwrap_or_else(|_| Block { actions: vec![], results: vec![] });
-                blocks.push(block);
-                acc_bytes += sz;
-            } else {
+        for bytes in store.blocks.range(start as usize..end as usize) {
+            let sz = bytes.len();
+            if acc_bytes + sz > MAX_BYTES && !blocks.is_empty() {
                 break;
             }
+            let block: Block = candid::decode_one(bytes.as_slice()).unwrap_or_else(|_| Block { header: None, actions: vec![], results: vec![], caller: None });
+            blocks.push(block);
+            acc_bytes += sz;
         }
         BlocksPage { total, start, blocks }
     })
@@ -1,57 +1,274 @@
-use app::types::{address::Address, block::Block, events::Event, meta::Meta};
-use staging_memory::traits::{CellStore, LogStore, MapStore};
+use app::store::{Backends, StoreBases};
+use app::types::{
+    address::Address,
+    allowance::{Allowance, AllowanceKey},
+    block::ChainTip,
+    events::Event,
+    meta::Meta,
+    token::{AccountKey, TokenId, TokenInfo},
+};
+use staging_memory::traits::{CellStore, LogStore, MapStore, ScratchStore, VersionedMapStore};
+use std::borrow::Cow;
+use std::ops::Bound;
 
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{cell::Cell as StableCell, log::Log as StableLog, BTreeMap as StableBTreeMap, DefaultMemoryImpl, Storable};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+// Staged account entries a layer may hold on the heap before it spills to stable memory.
+const ACCOUNTS_SPILL_THRESHOLD: usize = 10_000;
+
 thread_local! {
     static MEMORY_MANAGER: std::cell::RefCell<MemoryManager<DefaultMemoryImpl>> =
         std::cell::RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
 }
 
-pub struct StableMapBackend {
-    inner: StableBTreeMap<Vec<u8>, u128, Memory>,
+pub struct StableMapBackend<K: Storable + Ord + Clone, V: Storable> {
+    inner: StableBTreeMap<K, V, Memory>,
+    scratch: Option<StableScratch>,
 }
 
-impl StableMapBackend {
+impl<K: Storable + Ord + Clone, V: Storable> StableMapBackend<K, V> {
     pub fn new(mem: Memory) -> Self {
         let inner = StableBTreeMap::init(mem);
-        Self { inner }
+        Self { inner, scratch: None }
     }
 
     pub fn from_id(id: u8) -> Self {
         let mem = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)));
         Self::new(mem)
     }
+
+    /// Spill staged layers larger than `threshold` entries into the region `id`.
+    pub fn with_scratch(mut self, id: u8, threshold: usize) -> Self {
+        let mem = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)));
+        self.scratch = Some(StableScratch { inner: StableBTreeMap::init(mem), threshold });
+        self
+    }
+}
+
+// Scratch entries are keyed by scratch id (8 bytes BE) ++ key bytes; an empty value is a staged
+// removal, so only value types that never encode to zero bytes may spill.
+// The region is init'ed rather than reset so spilled layers survive an upgrade with `StagedLayers`.
+pub struct StableScratch {
+    inner: StableBTreeMap<Vec<u8>, Vec<u8>, Memory>,
+    threshold: usize,
+}
+
+impl StableScratch {
+    fn key(layer: u64, k: &[u8]) -> Vec<u8> {
+        let mut key = layer.to_be_bytes().to_vec();
+        key.extend_from_slice(k);
+        key
+    }
+
+    fn decode<V: Storable>(v: Vec<u8>) -> Option<V> {
+        if v.is_empty() {
+            return None;
+        }
+        Some(V::from_bytes(Cow::Owned(v)))
+    }
+}
+
+impl<K: Storable + Ord + Clone, V: Storable> ScratchStore<K, V> for StableScratch {
+    fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    fn get(&self, layer: u64, k: &K) -> Option<Option<V>> {
+        self.inner.get(&Self::key(layer, &k.to_bytes())).map(Self::decode)
+    }
+
+    fn insert(&mut self, layer: u64, k: K, v: Option<V>) {
+        let bytes = v.map(|v| v.to_bytes().into_owned()).unwrap_or_default();
+        self.inner.insert(Self::key(layer, &k.to_bytes()), bytes);
+    }
+
+    fn entries(&self, layer: u64, after: Option<&K>, limit: usize) -> Vec<(K, Option<V>)> {
+        let start = match after {
+            Some(a) => Bound::Excluded(Self::key(layer, &a.to_bytes())),
+            None => Bound::Included(Self::key(layer, &[])),
+        };
+        self.inner
+            .range((start, Bound::Unbounded))
+            .take_while(|(k, _)| k[..8] == layer.to_be_bytes())
+            .take(limit)
+            .map(|(k, v)| (K::from_bytes(Cow::Borrowed(&k[8..])), Self::decode(v)))
+            .collect()
+    }
+
+    fn clear(&mut self, layer: u64) {
+        let keys: Vec<Vec<u8>> = self
+            .inner
+            .range(Self::key(layer, &[])..)
+            .take_while(|(k, _)| k[..8] == layer.to_be_bytes())
+            .map(|(k, _)| k)
+            .collect();
+        for k in keys {
+            self.inner.remove(&k);
+        }
+    }
+}
+
+impl<K, V> MapStore<K, V> for StableMapBackend<K, V>
+where
+    K: Storable + Ord + Clone,
+    V: Storable + Clone,
+{
+    fn get(&self, k: &K) -> Option<V> {
+        self.inner.get(k)
+    }
+
+    fn put(&mut self, k: K, v: V) {
+        self.inner.insert(k, v);
+    }
+
+    fn remove(&mut self, k: &K) {
+        self.inner.remove(k);
+    }
+
+    fn keys(&self) -> Vec<K> {
+        self.inner.keys().collect()
+    }
+
+    fn scratch(&self) -> Option<&dyn ScratchStore<K, V>> {
+        self.scratch.as_ref().map(|s| s as &dyn ScratchStore<K, V>)
+    }
+
+    fn scratch_mut(&mut self) -> Option<&mut dyn ScratchStore<K, V>> {
+        self.scratch.as_mut().map(|s| s as &mut dyn ScratchStore<K, V>)
+    }
+}
+
+/// A `StableMapBackend` that also keeps every committed value by the version it was
+/// written at, for `get_at`. History keys are len(key) (4 bytes BE) ++ key ++ version
+/// (8 bytes BE) so one key's history ranges contiguously; an empty value marks a removal.
+/// The current version lives in stable memory too, so writes after an upgrade are not
+/// recorded at version 0.
+pub struct VersionedStableMap<K: Storable + Ord + Clone, V: Storable> {
+    current: StableMapBackend<K, V>,
+    history: StableBTreeMap<Vec<u8>, Vec<u8>, Memory>,
+    version: StableCell<u64, Memory>,
+}
+
+impl<K: Storable + Ord + Clone, V: Storable> VersionedStableMap<K, V> {
+    /// History goes to region `history_id` and the current version to `version_id`.
+    pub fn new(current: StableMapBackend<K, V>, history_id: u8, version_id: u8) -> Self {
+        let (history_mem, version_mem) = MEMORY_MANAGER.with(|m| {
+            let mm = m.borrow();
+            (mm.get(MemoryId::new(history_id)), mm.get(MemoryId::new(version_id)))
+        });
+        Self {
+            current,
+            history: StableBTreeMap::init(history_mem),
+            version: StableCell::init(version_mem, 0).expect("init version cell"),
+        }
+    }
+
+    fn history_key(k: &[u8], version: u64) -> Vec<u8> {
+        let mut key = Vec::with_capacity(4 + k.len() + 8);
+        key.extend_from_slice(&(k.len() as u32).to_be_bytes());
+        key.extend_from_slice(k);
+        key.extend_from_slice(&version.to_be_bytes());
+        key
+    }
+
+    fn record(&mut self, k: &K, v: Option<&V>) {
+        let bytes = v.map(|v| v.to_bytes().into_owned()).unwrap_or_default();
+        let key = Self::history_key(&k.to_bytes(), *self.version.get());
+        self.history.insert(key, bytes);
+    }
 }
 
-impl MapStore<Address, u128> for StableMapBackend {
-    fn get(&self, k: &Address) -> Option<u128> {
-        self.inner.get(&k.0)
+impl<K, V> MapStore<K, V> for VersionedStableMap<K, V>
+where
+    K: Storable + Ord + Clone,
+    V: Storable + Clone,
+{
+    fn get(&self, k: &K) -> Option<V> {
+        self.current.get(k)
+    }
+
+    fn put(&mut self, k: K, v: V) {
+        self.record(&k, Some(&v));
+        self.current.put(k, v);
     }
 
-    fn put(&mut self, k: Address, v: u128) {
-        self.inner.insert(k.0, v);
+    fn remove(&mut self, k: &K) {
+        self.record(k, None);
+        self.current.remove(k);
     }
 
-    fn remove(&mut self, k: &Address) {
-        self.inner.remove(&k.0);
+    fn keys(&self) -> Vec<K> {
+        self.current.keys()
     }
 
-    fn keys(&self) -> Vec<Address> {
-        self.inner.keys().map(Address).collect()
+    fn clear(&mut self) {
+        self.current.clear();
+        self.history.clear_new();
+        let _ = self.version.set(0);
+    }
+
+    fn set_version(&mut self, height: u64) {
+        let _ = self.version.set(height);
+    }
+
+    fn scratch(&self) -> Option<&dyn ScratchStore<K, V>> {
+        self.current.scratch()
+    }
+
+    fn scratch_mut(&mut self) -> Option<&mut dyn ScratchStore<K, V>> {
+        self.current.scratch_mut()
+    }
+}
+
+impl<K, V> VersionedMapStore<K, V> for VersionedStableMap<K, V>
+where
+    K: Storable + Ord + Clone,
+    V: Storable + Clone,
+{
+    fn get_at(&self, k: &K, height: u64) -> Option<V> {
+        let k = k.to_bytes();
+        self.history
+            .range(Self::history_key(&k, 0)..=Self::history_key(&k, height))
+            .next_back()
+            .and_then(|(_, v)| StableScratch::decode(v))
+    }
+
+    fn prune_before(&mut self, height: u64) {
+        // Per key, only the newest entry below `height` is still needed,
+        // and not even that one if it is a removal.
+        let mut drop: Vec<Vec<u8>> = Vec::new();
+        let mut last: Option<(Vec<u8>, bool)> = None;
+        for (key, value) in self.history.iter() {
+            let (k, h) = key.split_at(key.len() - 8);
+            if u64::from_be_bytes(h.try_into().expect("8-byte version suffix")) >= height {
+                continue;
+            }
+            if let Some((prev, removed)) = last.take() {
+                if &prev[..prev.len() - 8] == k || removed {
+                    drop.push(prev);
+                }
+            }
+            last = Some((key, value.is_empty()));
+        }
+        if let Some((prev, true)) = last {
+            drop.push(prev);
+        }
+        for k in drop {
+            self.history.remove(&k);
+        }
     }
 }
 
-pub struct StableCellBackend {
-    inner: StableCell<Meta, Memory>,
+pub struct StableCellBackend<T: Storable + Default + Clone> {
+    inner: StableCell<T, Memory>,
 }
 
-impl StableCellBackend {
+impl<T: Storable + Default + Clone> StableCellBackend<T> {
     pub fn new(mem: Memory) -> Self {
-        let cell = StableCell::init(mem, Meta::default()).expect("init stable cell");
+        let cell = StableCell::init(mem, T::default()).expect("init stable cell");
         Self { inner: cell }
     }
 
@@ -61,17 +278,17 @@ impl StableCellBackend {
     }
 }
 
-impl CellStore<Meta> for StableCellBackend {
-    fn get(&self) -> Option<Meta> {
+impl<T: Storable + Default + Clone> CellStore<T> for StableCellBackend<T> {
+    fn get(&self) -> Option<T> {
         Some(self.inner.get().clone())
     }
 
-    fn set(&mut self, v: Meta) {
+    fn set(&mut self, v: T) {
         let _ = self.inner.set(v);
     }
 
     fn clear(&mut self) {
-        let _ = self.inner.set(Meta::default());
+        let _ = self.inner.set(T::default());
     }
 }
 
@@ -108,6 +325,18 @@ where
         self.inner.get(idx as u64)
     }
 
+    fn get_range(&self, start: usize, end: usize) -> Vec<T> {
+        let end = end.min(self.inner.len() as usize);
+        let mut out = Vec::with_capacity(end.saturating_sub(start));
+        // Reuse one read buffer instead of allocating per entry.
+        let mut buf = Vec::new();
+        for idx in start..end {
+            self.inner.read_entry(idx as u64, &mut buf).expect("read log entry");
+            out.push(T::from_bytes(std::borrow::Cow::Borrowed(&buf)));
+        }
+        out
+    }
+
     fn append(&mut self, v: T) {
         let _ = self.inner.append(&v);
     }
@@ -124,16 +353,104 @@ where
     }
 }
 
-pub fn make_stable_backends() -> (
-    StableMapBackend,
-    StableCellBackend,
-    StableLogBackend<Event>,
-    StableLogBackend<Vec<u8>>,
-) {
-    (
-        StableMapBackend::from_id(0),
-        StableCellBackend::from_id(1),
-        StableLogBackend::from_ids(2, 3),
-        StableLogBackend::from_ids(4, 5),
-    )
+/// Candid-encoded `StagedLayers` parked here across an upgrade; empty when nothing is pending.
+pub struct StagedLayersCell {
+    inner: StableCell<Vec<u8>, Memory>,
+}
+
+impl StagedLayersCell {
+    pub fn from_id(id: u8) -> Self {
+        let mem = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)));
+        let inner = StableCell::init(mem, Vec::new()).expect("init staged layers cell");
+        Self { inner }
+    }
+
+    pub fn save(&mut self, bytes: Vec<u8>) {
+        self.inner.set(bytes).expect("save staged layers");
+    }
+
+    pub fn take(&mut self) -> Option<Vec<u8>> {
+        let bytes = self.inner.get().clone();
+        if bytes.is_empty() {
+            return None;
+        }
+        self.inner.set(Vec::new()).expect("clear staged layers");
+        Some(bytes)
+    }
+}
+
+/// Block heights of recent ICRC-1 transfers, keyed by `created_at_time` (8 bytes BE) ++
+/// transaction hash so the oldest entries come first.
+pub struct RecentTransfers {
+    inner: StableBTreeMap<Vec<u8>, u64, Memory>,
+}
+
+impl RecentTransfers {
+    pub fn from_id(id: u8) -> Self {
+        let mem = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)));
+        Self {
+            inner: StableBTreeMap::init(mem),
+        }
+    }
+
+    fn key(created_at_time: u64, tx_hash: &[u8]) -> Vec<u8> {
+        let mut key = created_at_time.to_be_bytes().to_vec();
+        key.extend_from_slice(tx_hash);
+        key
+    }
+
+    pub fn get(&self, created_at_time: u64, tx_hash: &[u8]) -> Option<u64> {
+        self.inner.get(&Self::key(created_at_time, tx_hash))
+    }
+
+    pub fn insert(&mut self, created_at_time: u64, tx_hash: &[u8], height: u64) {
+        self.inner.insert(Self::key(created_at_time, tx_hash), height);
+    }
+
+    /// Forgets every transfer created before `time`.
+    pub fn prune_before(&mut self, time: u64) {
+        while let Some((key, _)) = self.inner.first_key_value() {
+            let created = u64::from_be_bytes(key[..8].try_into().expect("8-byte time prefix"));
+            if created >= time {
+                break;
+            }
+            self.inner.remove(&key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.inner.clear_new();
+    }
+}
+
+pub struct StableBackends;
+
+impl Backends for StableBackends {
+    type Accounts = VersionedStableMap<Address, u128>;
+    type Nonces = StableMapBackend<Address, u64>;
+    type TokenAccounts = StableMapBackend<AccountKey, u128>;
+    type Tokens = StableMapBackend<TokenId, TokenInfo>;
+    type Allowances = StableMapBackend<AllowanceKey, Allowance>;
+    type Meta = StableCellBackend<Meta>;
+    type Events = StableLogBackend<Event>;
+    type Blocks = StableLogBackend<Vec<u8>>;
+    type Tip = StableCellBackend<ChainTip>;
+}
+
+pub fn make_stable_backends() -> StoreBases<StableBackends> {
+    StoreBases {
+        accounts: VersionedStableMap::new(
+            StableMapBackend::from_id(0).with_scratch(7, ACCOUNTS_SPILL_THRESHOLD),
+            15,
+            16,
+        ),
+        nonces: StableMapBackend::from_id(8),
+        token_accounts: StableMapBackend::from_id(9).with_scratch(10, ACCOUNTS_SPILL_THRESHOLD),
+        tokens: StableMapBackend::from_id(11),
+        allowances: StableMapBackend::from_id(12),
+        meta: StableCellBackend::from_id(1),
+        events: StableLogBackend::from_ids(2, 3),
+        blocks: StableLogBackend::from_ids(4, 5),
+        tip: StableCellBackend::from_id(13),
+    }
 }
@@ -1,4 +1,10 @@
-use crate::{overlay::Overlay, traits::MapStore};
+use crate::{
+    overlay::Overlay,
+    traits::{Layered, MapStore, ScratchStore, VersionedMapStore},
+};
+
+// Entries moved per round trip when draining a spilled layer.
+const SCRATCH_CHUNK: usize = 1024;
 
 #[derive(Debug)]
 pub struct BTreeTxn<K, V, B>
@@ -9,6 +15,7 @@ where
 {
     base: B,
     overlays: Vec<Overlay<K, V>>, // top is last
+    next_scratch_id: u64,
 }
 
 impl<K, V, B> BTreeTxn<K, V, B>
@@ -21,6 +28,7 @@ where
         Self {
             base,
             overlays: vec![Overlay::new()],
+            next_scratch_id: 0,
         }
     }
 
@@ -30,27 +38,22 @@ where
 
     pub fn revert_top(&mut self) {
         if self.overlays.len() > 1 {
-            self.overlays.pop();
+            let top = self.overlays.pop().unwrap();
+            self.discard(top);
         } else {
-            self.overlays[0].staged.clear();
+            let top = std::mem::take(&mut self.overlays[0]);
+            self.discard(top);
         }
     }
 
     pub fn commit_top(&mut self) {
         if self.overlays.len() > 1 {
             let top = self.overlays.pop().unwrap();
-            let next = self.overlays.last_mut().unwrap();
-            for (k, v) in top.staged {
-                next.staged.insert(k, v);
-            }
+            let next = self.overlays.len() - 1;
+            self.drain(top, |txn, k, v| txn.stage(next, k, v));
         } else {
             let top = self.overlays.pop().unwrap();
-            for (k, v) in top.staged {
-                match v {
-                    Some(val) => self.base.put(k, val),
-                    None => self.base.remove(&k),
-                }
-            }
+            self.drain(top, Self::apply_to_base);
             self.overlays.push(Overlay::new());
         }
     }
@@ -67,42 +70,112 @@ where
             return;
         }
         let oldest = self.overlays.remove(0);
-        for (k, v) in oldest.staged {
-            match v {
-                Some(val) => self.base.put(k, val),
-                None => self.base.remove(&k),
-            }
-        }
+        self.drain(oldest, Self::apply_to_base);
         if self.overlays.is_empty() {
             self.overlays.push(Overlay::new());
         }
     }
 
     pub fn insert(&mut self, k: K, v: V) {
-        self.overlays
-            .last_mut()
-            .expect("at least one layer")
-            .staged
-            .insert(k, Some(v));
+        self.stage(self.top(), k, Some(v));
     }
 
     pub fn remove(&mut self, k: &K) {
-        self.overlays
-            .last_mut()
-            .expect("at least one layer")
-            .staged
-            .insert(k.clone(), None);
+        self.stage(self.top(), k.clone(), None);
     }
 
     pub fn get(&self, k: &K) -> Option<V> {
         for layer in self.overlays.iter().rev() {
-            if let Some(v) = layer.staged.get(k) {
-                return v.clone();
+            if let Some(v) = self.layer_get(layer, k) {
+                return v;
             }
         }
         self.base.get(k)
     }
 
+    pub fn insert_many<I: IntoIterator<Item = (K, V)>>(&mut self, items: I) {
+        let top = self.top();
+        for (k, v) in items {
+            self.stage(top, k, Some(v));
+        }
+    }
+
+    /// Looks up all keys in one pass over the layer stack, falling back to the base
+    /// only for keys no layer has staged.
+    pub fn get_many(&self, keys: &[K]) -> Vec<Option<V>> {
+        let mut out: Vec<Option<Option<V>>> = vec![None; keys.len()];
+        for layer in self.overlays.iter().rev() {
+            for (slot, k) in out.iter_mut().zip(keys) {
+                if slot.is_none() {
+                    *slot = self.layer_get(layer, k);
+                }
+            }
+        }
+        out.into_iter()
+            .zip(keys)
+            .map(|(slot, k)| slot.unwrap_or_else(|| self.base.get(k)))
+            .collect()
+    }
+
+    /// Reads `k` once and hands the value to `f`; `Some` is staged as an insert,
+    /// `None` as a removal (nothing is staged if the key was absent and stays absent).
+    pub fn update<F>(&mut self, k: K, f: F)
+    where
+        F: FnOnce(Option<V>) -> Option<V>,
+    {
+        let cur = self.get(&k);
+        let existed = cur.is_some();
+        match f(cur) {
+            Some(v) => self.insert(k, v),
+            None if existed => self.remove(&k),
+            None => {}
+        }
+    }
+
+    pub fn entry(&mut self, k: K) -> Entry<'_, K, V, B> {
+        let value = self.get(&k);
+        Entry {
+            txn: self,
+            key: k,
+            value,
+        }
+    }
+
+    /// Keys staged in the top layer, in order.
+    pub fn top_keys(&self) -> Vec<K> {
+        self.layer_keys(&self.overlays[self.top()])
+    }
+
+    /// Value of `k` as the layers beneath the top one see it.
+    pub fn get_below_top(&self, k: &K) -> Option<V> {
+        for layer in self.overlays[..self.top()].iter().rev() {
+            if let Some(v) = self.layer_get(layer, k) {
+                return v;
+            }
+        }
+        self.base.get(k)
+    }
+
+    /// Committed value of `k` as of `height`; staged layers are not consulted.
+    pub fn get_at(&self, k: &K, height: u64) -> Option<V>
+    where
+        B: VersionedMapStore<K, V>,
+    {
+        self.base.get_at(k, height)
+    }
+
+    /// Sets the version the next commit into the base is recorded at.
+    pub fn set_base_version(&mut self, height: u64) {
+        self.base.set_version(height);
+    }
+
+    pub fn prune_history_before(&mut self, height: u64)
+    where
+        B: VersionedMapStore<K, V>,
+    {
+        self.base.prune_before(height);
+    }
+
     pub fn iter_effective<'a>(&'a self) -> BTreeEffectiveIter<'a, K, V, B> {
         BTreeEffectiveIter::new(self)
     }
@@ -112,10 +185,218 @@ where
     }
 
     pub fn clear_all(&mut self) {
+        for layer in std::mem::take(&mut self.overlays) {
+            self.discard(layer);
+        }
         self.base.clear();
-        self.overlays.clear();
         self.overlays.push(Overlay::new());
     }
+
+    /// Staged layers, oldest first. Together with `restore_layers` this lets
+    /// callers persist uncommitted state without touching the base. Spilled layers
+    /// only carry their scratch id; their entries stay in the base's scratch store.
+    pub fn layers(&self) -> &[Overlay<K, V>] {
+        &self.overlays
+    }
+
+    /// Replaces the staged layers. An empty vector leaves a single empty layer.
+    pub fn restore_layers(&mut self, layers: Vec<Overlay<K, V>>) {
+        self.overlays = layers;
+        if self.overlays.is_empty() {
+            self.overlays.push(Overlay::new());
+        }
+        self.next_scratch_id = self
+            .overlays
+            .iter()
+            .filter_map(|l| l.spilled)
+            .max()
+            .map_or(0, |id| id + 1);
+    }
+
+    fn top(&self) -> usize {
+        self.overlays.len() - 1
+    }
+
+    fn scratch(&self) -> &dyn ScratchStore<K, V> {
+        self.base.scratch().expect("spilled layer without scratch store")
+    }
+
+    fn scratch_mut(&mut self) -> &mut dyn ScratchStore<K, V> {
+        self.base.scratch_mut().expect("spilled layer without scratch store")
+    }
+
+    fn layer_get(&self, layer: &Overlay<K, V>, k: &K) -> Option<Option<V>> {
+        match layer.spilled {
+            Some(id) => self.scratch().get(id, k),
+            None => layer.staged.get(k).cloned(),
+        }
+    }
+
+    fn layer_keys(&self, layer: &Overlay<K, V>) -> Vec<K> {
+        match layer.spilled {
+            Some(id) => {
+                let mut keys = Vec::new();
+                loop {
+                    let chunk = self.scratch().entries(id, keys.last(), SCRATCH_CHUNK);
+                    let done = chunk.len() < SCRATCH_CHUNK;
+                    keys.extend(chunk.into_iter().map(|(k, _)| k));
+                    if done {
+                        return keys;
+                    }
+                }
+            }
+            None => layer.staged.keys().cloned().collect(),
+        }
+    }
+
+    // Writes into layer `idx`, moving it to scratch once it passes the threshold.
+    fn stage(&mut self, idx: usize, k: K, v: Option<V>) {
+        if let Some(id) = self.overlays[idx].spilled {
+            self.scratch_mut().insert(id, k, v);
+            return;
+        }
+        self.overlays[idx].staged.insert(k, v);
+        let Some(scratch) = self.base.scratch_mut() else {
+            return;
+        };
+        let layer = &mut self.overlays[idx];
+        if layer.staged.len() > scratch.threshold() {
+            let id = self.next_scratch_id;
+            self.next_scratch_id += 1;
+            for (k, v) in std::mem::take(&mut layer.staged) {
+                scratch.insert(id, k, v);
+            }
+            layer.spilled = Some(id);
+        }
+    }
+
+    // Feeds every entry of a detached layer to `f`, then releases its scratch space.
+    fn drain<F>(&mut self, layer: Overlay<K, V>, mut f: F)
+    where
+        F: FnMut(&mut Self, K, Option<V>),
+    {
+        match layer.spilled {
+            Some(id) => {
+                let mut after: Option<K> = None;
+                loop {
+                    let chunk = self.scratch().entries(id, after.as_ref(), SCRATCH_CHUNK);
+                    let done = chunk.len() < SCRATCH_CHUNK;
+                    after = chunk.last().map(|(k, _)| k.clone());
+                    for (k, v) in chunk {
+                        f(self, k, v);
+                    }
+                    if done {
+                        break;
+                    }
+                }
+                self.scratch_mut().clear(id);
+            }
+            None => {
+                for (k, v) in layer.staged {
+                    f(self, k, v);
+                }
+            }
+        }
+    }
+
+    fn discard(&mut self, layer: Overlay<K, V>) {
+        if let Some(id) = layer.spilled {
+            self.scratch_mut().clear(id);
+        }
+    }
+
+    fn apply_to_base(&mut self, k: K, v: Option<V>) {
+        match v {
+            Some(val) => self.base.put(k, val),
+            None => self.base.remove(&k),
+        }
+    }
+}
+
+impl<K, V, B> Layered for BTreeTxn<K, V, B>
+where
+    K: Ord + Clone,
+    V: Clone,
+    B: MapStore<K, V>,
+{
+    fn push_layer(&mut self) {
+        BTreeTxn::push_layer(self)
+    }
+
+    fn commit_top(&mut self) {
+        BTreeTxn::commit_top(self)
+    }
+
+    fn revert_top(&mut self) {
+        BTreeTxn::revert_top(self)
+    }
+}
+
+/// A key looked up once in a `BTreeTxn`. Modifications are staged in the top layer
+/// as they are made, so chained calls never walk the layer stack again.
+pub struct Entry<'a, K, V, B>
+where
+    K: Ord + Clone,
+    V: Clone,
+    B: MapStore<K, V>,
+{
+    txn: &'a mut BTreeTxn<K, V, B>,
+    key: K,
+    value: Option<V>,
+}
+
+impl<'a, K, V, B> Entry<'a, K, V, B>
+where
+    K: Ord + Clone,
+    V: Clone,
+    B: MapStore<K, V>,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn get(&self) -> Option<&V> {
+        self.value.as_ref()
+    }
+
+    pub fn or_insert(self, default: V) -> Self {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(mut self, f: F) -> Self {
+        if self.value.is_none() {
+            let v = f();
+            self.txn.insert(self.key.clone(), v.clone());
+            self.value = Some(v);
+        }
+        self
+    }
+
+    pub fn or_default(self) -> Self
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Some(v) = self.value.as_mut() {
+            f(v);
+            self.txn.insert(self.key.clone(), v.clone());
+        }
+        self
+    }
+
+    pub fn remove(self) -> Option<V> {
+        if self.value.is_some() {
+            self.txn.remove(&self.key);
+        }
+        self.value
+    }
+
+    pub fn into_value(self) -> Option<V> {
+        self.value
+    }
 }
 
 pub struct BTreeEffectiveIter<'a, K, V, B>
@@ -142,7 +423,7 @@ where
             keys.insert(k, ());
         }
         for layer in &txn.overlays {
-            for k in layer.staged.keys().cloned() {
+            for k in txn.layer_keys(layer) {
                 keys.insert(k, ());
             }
         }
@@ -173,3 +454,235 @@ where
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::SpillingInMemoryMap;
+
+    type Txn = BTreeTxn<u32, u32, SpillingInMemoryMap<u32, u32>>;
+
+    // Spills a layer once it holds more than two entries.
+    fn txn() -> Txn {
+        BTreeTxn::new(SpillingInMemoryMap::new(2))
+    }
+
+    fn scratch_ids(txn: &Txn) -> Vec<u64> {
+        txn.base.scratch.layer_ids()
+    }
+
+    #[test]
+    fn layer_spills_past_threshold() {
+        let mut txn = txn();
+        txn.push_layer();
+        txn.insert(1, 10);
+        txn.insert(2, 20);
+        assert_eq!(txn.layers()[1].spilled, None);
+        txn.insert(3, 30);
+        assert_eq!(txn.layers()[1].spilled, Some(0));
+        assert!(txn.layers()[1].staged.is_empty());
+        txn.remove(&1);
+        txn.insert(4, 40);
+        assert_eq!(txn.get(&1), None);
+        assert_eq!(txn.get(&4), Some(40));
+        assert_eq!(txn.top_keys(), vec![1, 2, 3, 4]);
+        assert_eq!(
+            txn.iter_effective().collect::<Vec<_>>(),
+            vec![(2, 20), (3, 30), (4, 40)]
+        );
+    }
+
+    #[test]
+    fn commit_spilled_layer_into_staged_one() {
+        let mut txn = txn();
+        txn.insert(1, 1);
+        txn.push_layer();
+        txn.insert(2, 2);
+        txn.remove(&1);
+        txn.insert(3, 3);
+        assert_eq!(txn.layers()[1].spilled, Some(0));
+        txn.commit_top();
+        // The merged layer crosses the threshold itself and spills under a new id.
+        assert_eq!(txn.layers().len(), 1);
+        assert_eq!(txn.layers()[0].spilled, Some(1));
+        assert_eq!(scratch_ids(&txn), vec![1]);
+        assert_eq!(txn.get(&1), None);
+        assert_eq!(txn.get(&3), Some(3));
+        txn.commit_top();
+        assert!(scratch_ids(&txn).is_empty());
+        assert_eq!(txn.base.map.keys(), vec![2, 3]);
+    }
+
+    #[test]
+    fn commit_spilled_layer_into_empty_one() {
+        let mut txn = txn();
+        txn.push_layer();
+        txn.push_layer();
+        for k in 0..3 {
+            txn.insert(k, k);
+        }
+        txn.commit_top();
+        assert_eq!(txn.layers().len(), 2);
+        assert_eq!(txn.layers()[1].spilled, Some(1));
+        assert_eq!(scratch_ids(&txn), vec![1]);
+        txn.commit_all();
+        assert!(scratch_ids(&txn).is_empty());
+        assert_eq!(txn.base.map.keys(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn revert_releases_scratch() {
+        let mut txn = txn();
+        txn.insert(1, 1);
+        txn.commit_top();
+        txn.push_layer();
+        for k in 1..5 {
+            txn.insert(k, 100 + k);
+        }
+        assert_eq!(scratch_ids(&txn), vec![0]);
+        txn.revert_top();
+        assert!(scratch_ids(&txn).is_empty());
+        assert_eq!(txn.get(&1), Some(1));
+        assert_eq!(txn.get(&2), None);
+
+        for k in 1..5 {
+            txn.insert(k, k);
+        }
+        txn.clear_all();
+        assert!(scratch_ids(&txn).is_empty());
+    }
+
+    #[test]
+    fn restore_continues_after_highest_scratch_id() {
+        let mut txn = txn();
+        txn.push_layer();
+        for k in 0..3 {
+            txn.insert(k, k);
+        }
+        txn.push_layer();
+        txn.insert(9, 9);
+        txn.push_layer();
+        for k in 10..13 {
+            txn.insert(k, k);
+        }
+        assert_eq!(scratch_ids(&txn), vec![0, 1]);
+
+        // As after an upgrade: a fresh txn over the same base and scratch.
+        let layers = txn.layers().to_vec();
+        let BTreeTxn { base, .. } = txn;
+        let mut txn = BTreeTxn::new(base);
+        txn.restore_layers(layers);
+        assert_eq!(txn.get(&1), Some(1));
+        assert_eq!(txn.get(&9), Some(9));
+        assert_eq!(txn.get(&11), Some(11));
+
+        txn.push_layer();
+        for k in 20..23 {
+            txn.insert(k, k);
+        }
+        assert_eq!(txn.layers()[4].spilled, Some(2));
+        assert_eq!(txn.get(&1), Some(1));
+        assert_eq!(txn.get(&11), Some(11));
+    }
+
+    // 1 and 2 committed to the base; a layer above removes 1 and stages 3; a fresh top
+    // layer above that.
+    fn layered() -> Txn {
+        let mut txn = txn();
+        txn.insert_many([(1, 10), (2, 20)]);
+        txn.commit_top();
+        txn.push_layer();
+        txn.remove(&1);
+        txn.insert(3, 30);
+        txn.push_layer();
+        txn
+    }
+
+    #[test]
+    fn entry_reads_through_layers_and_tombstones() {
+        let mut txn = layered();
+        assert_eq!(txn.entry(1).get(), None);
+        assert_eq!(txn.entry(2).get(), Some(&20));
+        assert_eq!(txn.entry(3).get(), Some(&30));
+        assert_eq!(txn.entry(2).key(), &2);
+        assert!(txn.top_keys().is_empty());
+    }
+
+    #[test]
+    fn entry_or_insert_stages_only_absent_keys() {
+        let mut txn = layered();
+        assert_eq!(txn.entry(1).or_insert(11).into_value(), Some(11));
+        assert_eq!(txn.entry(2).or_insert(22).into_value(), Some(20));
+        assert_eq!(txn.entry(4).or_default().into_value(), Some(0));
+        assert_eq!(txn.top_keys(), vec![1, 4]);
+        txn.revert_top();
+        assert_eq!(txn.get(&1), None);
+        assert_eq!(txn.get(&4), None);
+    }
+
+    #[test]
+    fn entry_and_modify_stages_in_the_top_layer() {
+        let mut txn = layered();
+        let v = txn.entry(2).and_modify(|v| *v += 1).into_value();
+        assert_eq!(v, Some(21));
+        // A tombstoned key has nothing to modify.
+        assert_eq!(txn.entry(1).and_modify(|v| *v += 1).into_value(), None);
+        assert_eq!(txn.top_keys(), vec![2]);
+        assert_eq!(txn.get_below_top(&2), Some(20));
+        txn.revert_top();
+        assert_eq!(txn.get(&2), Some(20));
+    }
+
+    #[test]
+    fn entry_remove_shadows_lower_layers() {
+        let mut txn = layered();
+        assert_eq!(txn.entry(2).remove(), Some(20));
+        assert_eq!(txn.entry(3).remove(), Some(30));
+        // Already removed below: nothing new is staged.
+        assert_eq!(txn.entry(1).remove(), None);
+        assert_eq!(txn.top_keys(), vec![2, 3]);
+        assert_eq!(txn.get(&2), None);
+        assert_eq!(txn.get(&3), None);
+        txn.commit_all();
+        assert_eq!(txn.base.map.keys(), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn update_inserts_modifies_and_removes() {
+        let mut txn = layered();
+        txn.update(1, |v| {
+            assert_eq!(v, None);
+            Some(1)
+        });
+        txn.update(2, |v| v.map(|v| v * 2));
+        txn.update(3, |_| None);
+        txn.update(4, |_| None);
+        assert_eq!(txn.get_many(&[1, 2, 3, 4]), vec![Some(1), Some(40), None, None]);
+        // 4 was absent and stays absent, so it leaves no tombstone.
+        assert_eq!(txn.top_keys(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn get_many_mixes_layers_tombstones_and_base() {
+        let mut txn = layered();
+        txn.insert(2, 21);
+        assert_eq!(
+            txn.get_many(&[1, 2, 3, 4, 2]),
+            vec![None, Some(21), Some(30), None, Some(21)]
+        );
+        assert!(txn.get_many(&[]).is_empty());
+    }
+
+    #[test]
+    fn insert_many_stages_in_the_top_layer_and_spills() {
+        let mut txn = layered();
+        txn.insert_many([(1, 11), (5, 50)]);
+        assert_eq!(txn.layers()[2].spilled, None);
+        txn.insert_many([(6, 60)]);
+        assert_eq!(txn.layers()[2].spilled, Some(0));
+        assert_eq!(txn.get_many(&[1, 5, 6, 3]), vec![Some(11), Some(50), Some(60), Some(30)]);
+        txn.revert_top();
+        assert_eq!(txn.get_many(&[1, 5, 6]), vec![None, None, None]);
+        assert!(scratch_ids(&txn).is_empty());
+    }
+}
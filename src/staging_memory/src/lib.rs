@@ -4,3 +4,5 @@ pub mod mem;
 pub mod btree;
 pub mod struct_store;
 pub mod log;
+pub mod versioned;
+pub mod shared;
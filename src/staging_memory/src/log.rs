@@ -1,4 +1,10 @@
-use crate::traits::LogStore;
+use std::collections::VecDeque;
+use std::ops::{Bound, RangeBounds};
+
+use crate::traits::{Layered, LogStore};
+
+// Base items fetched per `get_range` call while iterating.
+const BASE_CHUNK: usize = 256;
 
 #[derive(Debug)]
 pub struct LogTxn<T: Clone, B: LogStore<T>> {
@@ -80,6 +86,56 @@ impl<T: Clone, B: LogStore<T>> LogTxn<T, B> {
         None
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Entries already committed to the base.
+    pub fn committed_len(&self) -> usize {
+        self.base.len()
+    }
+
+    pub fn last(&self) -> Option<T> {
+        self.len().checked_sub(1).and_then(|i| self.get(i))
+    }
+
+    pub fn iter(&self) -> LogIter<'_, T, B> {
+        self.range(..)
+    }
+
+    pub fn iter_rev(&self) -> std::iter::Rev<LogIter<'_, T, B>> {
+        self.iter().rev()
+    }
+
+    /// Streams `range` (clamped to `len()`) across the base and the staged layers,
+    /// reading the base in chunks rather than one `get` per item.
+    pub fn range<R: RangeBounds<usize>>(&self, range: R) -> LogIter<'_, T, B> {
+        let len = self.len();
+        let start = match range.start_bound() {
+            Bound::Included(&s) => s,
+            Bound::Excluded(&s) => s.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&e) => e.saturating_add(1),
+            Bound::Excluded(&e) => e,
+            Bound::Unbounded => len,
+        };
+        let end = end.min(len);
+        LogIter {
+            txn: self,
+            front: start.min(end),
+            back: end,
+            buf: VecDeque::new(),
+            back_buf: VecDeque::new(),
+        }
+    }
+
+    /// Items in `start..end`, clamped to `len()`.
+    pub fn get_range(&self, start: usize, end: usize) -> Vec<T> {
+        self.range(start..end).collect()
+    }
+
     pub fn clear(&mut self) {
         self.base.clear();
         for layer in &mut self.overlays {
@@ -92,4 +148,217 @@ impl<T: Clone, B: LogStore<T>> LogTxn<T, B> {
         self.overlays.clear();
         self.overlays.push(Vec::new());
     }
+
+    pub fn layers(&self) -> &[Vec<T>] {
+        &self.overlays
+    }
+
+    pub fn restore_layers(&mut self, layers: Vec<Vec<T>>) {
+        self.overlays = layers;
+        if self.overlays.is_empty() {
+            self.overlays.push(Vec::new());
+        }
+    }
+}
+
+impl<T: Clone, B: LogStore<T>> Layered for LogTxn<T, B> {
+    fn push_layer(&mut self) {
+        LogTxn::push_layer(self)
+    }
+
+    fn commit_top(&mut self) {
+        LogTxn::commit_top(self)
+    }
+
+    fn revert_top(&mut self) {
+        LogTxn::revert_top(self)
+    }
+}
+
+pub struct LogIter<'a, T: Clone, B: LogStore<T>> {
+    txn: &'a LogTxn<T, B>,
+    front: usize, // index of the next item from the front
+    back: usize, // one past the next item from the back
+    buf: VecDeque<T>, // prefetched items starting at `front`
+    back_buf: VecDeque<T>, // prefetched items ending at `back`, never overlapping `buf`
+}
+
+impl<T: Clone, B: LogStore<T>> Iterator for LogIter<'_, T, B> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.front >= self.back {
+            return None;
+        }
+        if self.buf.is_empty() {
+            let unbuffered_end = self.back - self.back_buf.len();
+            if self.front == unbuffered_end {
+                // Everything left was prefetched from the back.
+                let v = self.back_buf.pop_front()?;
+                self.front += 1;
+                return Some(v);
+            }
+            let base_len = self.txn.base.len();
+            if self.front < base_len {
+                let end = (self.front + BASE_CHUNK).min(base_len).min(unbuffered_end);
+                self.buf.extend(self.txn.base.get_range(self.front, end));
+            } else if let Some(v) = self.txn.get(self.front) {
+                self.buf.push_back(v);
+            }
+        }
+        let v = self.buf.pop_front()?;
+        self.front += 1;
+        Some(v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.back.saturating_sub(self.front);
+        (n, Some(n))
+    }
+}
+
+impl<T: Clone, B: LogStore<T>> DoubleEndedIterator for LogIter<'_, T, B> {
+    fn next_back(&mut self) -> Option<T> {
+        if self.front >= self.back {
+            return None;
+        }
+        if self.back_buf.is_empty() {
+            let unbuffered_start = self.front + self.buf.len();
+            if self.back == unbuffered_start {
+                // Everything left was prefetched from the front.
+                let v = self.buf.pop_back()?;
+                self.back -= 1;
+                return Some(v);
+            }
+            if self.back <= self.txn.base.len() {
+                let start = self.back.saturating_sub(BASE_CHUNK).max(unbuffered_start);
+                self.back_buf.extend(self.txn.base.get_range(start, self.back));
+            } else if let Some(v) = self.txn.get(self.back - 1) {
+                self.back_buf.push_back(v);
+            }
+        }
+        let v = self.back_buf.pop_back()?;
+        self.back -= 1;
+        Some(v)
+    }
+}
+
+impl<T: Clone, B: LogStore<T>> ExactSizeIterator for LogIter<'_, T, B> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::InMemoryLog;
+    use std::cell::Cell;
+
+    // Counts the reads that reach the base.
+    #[derive(Default)]
+    struct CountingLog {
+        inner: InMemoryLog<u32>,
+        reads: Cell<usize>,
+    }
+
+    impl LogStore<u32> for CountingLog {
+        fn len(&self) -> usize {
+            self.inner.len()
+        }
+        fn get(&self, idx: usize) -> Option<u32> {
+            self.reads.set(self.reads.get() + 1);
+            self.inner.get(idx)
+        }
+        fn get_range(&self, start: usize, end: usize) -> Vec<u32> {
+            self.reads.set(self.reads.get() + 1);
+            self.inner.get_range(start, end)
+        }
+        fn append(&mut self, v: u32) {
+            self.inner.append(v)
+        }
+        fn extend<I: IntoIterator<Item = u32>>(&mut self, it: I) {
+            self.inner.extend(it)
+        }
+        fn clear(&mut self) {
+            self.inner.clear()
+        }
+    }
+
+    // Base items 0..600 (past two fetch chunks), then layers holding 600..603 and 603..605.
+    fn log() -> LogTxn<u32, InMemoryLog<u32>> {
+        let mut base = InMemoryLog::new();
+        base.extend(0..600);
+        let mut log = LogTxn::new(base);
+        (600..603).for_each(|v| log.append(v));
+        log.push_layer();
+        log.push_layer();
+        (603..605).for_each(|v| log.append(v));
+        log
+    }
+
+    #[test]
+    fn iterates_base_and_layers_both_ways() {
+        let log = log();
+        assert_eq!(log.iter().collect::<Vec<_>>(), (0..605).collect::<Vec<_>>());
+        assert_eq!(log.iter_rev().collect::<Vec<_>>(), (0..605).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn ends_meet_inside_prefetched_chunk() {
+        let log = log();
+        let mut it = log.range(250..260);
+        assert_eq!(it.next(), Some(250));
+        assert_eq!(it.next_back(), Some(259));
+        assert_eq!(it.len(), 8);
+        let mut seen = Vec::new();
+        while let Some(v) = it.next_back() {
+            seen.push(v);
+            if let Some(v) = it.next() {
+                seen.push(v);
+            }
+        }
+        assert_eq!(seen, vec![258, 251, 257, 252, 256, 253, 255, 254]);
+        assert_eq!(it.next(), None);
+        assert_eq!(it.next_back(), None);
+    }
+
+    #[test]
+    fn ends_meet_across_base_and_layers() {
+        let log = log();
+        let mut it = log.range(598..);
+        assert_eq!(it.next_back(), Some(604));
+        assert_eq!(it.next(), Some(598));
+        assert_eq!(it.next_back(), Some(603));
+        assert_eq!(it.next_back(), Some(602));
+        assert_eq!(it.collect::<Vec<_>>(), vec![599, 600, 601]);
+    }
+
+    #[test]
+    fn range_is_clamped() {
+        let log = log();
+        assert_eq!(log.range(603..700).collect::<Vec<_>>(), vec![603, 604]);
+        assert_eq!(log.range(700..800).count(), 0);
+        assert_eq!(log.range(604..=604).collect::<Vec<_>>(), vec![604]);
+        assert_eq!(log.get_range(599, 601), vec![599, 600]);
+    }
+
+    #[test]
+    fn reads_base_in_chunks_both_ways() {
+        let mut base = CountingLog::default();
+        base.extend(0..600);
+        let log = LogTxn::new(base);
+        assert_eq!(log.iter().count(), 600);
+        assert_eq!(log.base.reads.replace(0), 3);
+        assert_eq!(log.iter_rev().collect::<Vec<_>>(), (0..600).rev().collect::<Vec<_>>());
+        assert_eq!(log.base.reads.replace(0), 3);
+    }
+
+    #[test]
+    fn ends_meet_inside_chunk_prefetched_from_the_back() {
+        let log = log();
+        let mut it = log.range(250..260);
+        assert_eq!(it.next_back(), Some(259));
+        assert_eq!(it.next(), Some(250));
+        assert_eq!(it.next(), Some(251));
+        assert_eq!(it.len(), 7);
+        assert_eq!(it.next_back(), Some(258));
+        assert_eq!(it.collect::<Vec<_>>(), (252..258).collect::<Vec<_>>());
+    }
 }
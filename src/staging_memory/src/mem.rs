@@ -1,6 +1,7 @@
 use std::collections::BTreeMap;
+use std::ops::Bound;
 
-use crate::traits::{CellStore, LogStore, MapStore};
+use crate::traits::{CellStore, LogStore, MapStore, ScratchStore, VersionedMapStore};
 
 #[derive(Debug, Default)]
 pub struct InMemoryMap<K, V>
@@ -49,6 +50,161 @@ where
     }
 }
 
+/// Scratch space kept in a `BTreeMap`, one inner map per spilled layer.
+#[derive(Debug, Default)]
+pub struct InMemoryScratch<K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    threshold: usize,
+    layers: BTreeMap<u64, BTreeMap<K, Option<V>>>,
+}
+
+impl<K, V> InMemoryScratch<K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            layers: BTreeMap::new(),
+        }
+    }
+
+    /// Ids of the layers currently holding entries.
+    pub fn layer_ids(&self) -> Vec<u64> {
+        self.layers.keys().copied().collect()
+    }
+}
+
+impl<K, V> ScratchStore<K, V> for InMemoryScratch<K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    fn get(&self, layer: u64, k: &K) -> Option<Option<V>> {
+        self.layers.get(&layer)?.get(k).cloned()
+    }
+
+    fn insert(&mut self, layer: u64, k: K, v: Option<V>) {
+        self.layers.entry(layer).or_default().insert(k, v);
+    }
+
+    fn entries(&self, layer: u64, after: Option<&K>, limit: usize) -> Vec<(K, Option<V>)> {
+        let Some(entries) = self.layers.get(&layer) else {
+            return Vec::new();
+        };
+        let from = match after {
+            Some(k) => Bound::Excluded(k),
+            None => Bound::Unbounded,
+        };
+        entries
+            .range((from, Bound::Unbounded))
+            .take(limit)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    fn clear(&mut self, layer: u64) {
+        self.layers.remove(&layer);
+    }
+}
+
+/// An in-memory map, an `InMemoryMap` unless given another, whose staged layers spill
+/// into an `InMemoryScratch`, the way they do over stable memory.
+#[derive(Debug, Default)]
+pub struct SpillingInMemoryMap<K, V, M = InMemoryMap<K, V>>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub map: M,
+    pub scratch: InMemoryScratch<K, V>,
+}
+
+impl<K, V> SpillingInMemoryMap<K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn new(threshold: usize) -> Self {
+        Self::over(InMemoryMap::new(), threshold)
+    }
+}
+
+impl<K, V, M> SpillingInMemoryMap<K, V, M>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn over(map: M, threshold: usize) -> Self {
+        Self {
+            map,
+            scratch: InMemoryScratch::new(threshold),
+        }
+    }
+}
+
+impl<K, V, M> MapStore<K, V> for SpillingInMemoryMap<K, V, M>
+where
+    K: Ord + Clone,
+    V: Clone,
+    M: MapStore<K, V>,
+{
+    fn get(&self, k: &K) -> Option<V> {
+        self.map.get(k)
+    }
+
+    fn put(&mut self, k: K, v: V) {
+        self.map.put(k, v);
+    }
+
+    fn remove(&mut self, k: &K) {
+        self.map.remove(k);
+    }
+
+    fn keys(&self) -> Vec<K> {
+        self.map.keys()
+    }
+
+    fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    fn set_version(&mut self, height: u64) {
+        self.map.set_version(height);
+    }
+
+    fn scratch(&self) -> Option<&dyn ScratchStore<K, V>> {
+        Some(&self.scratch)
+    }
+
+    fn scratch_mut(&mut self) -> Option<&mut dyn ScratchStore<K, V>> {
+        Some(&mut self.scratch)
+    }
+}
+
+impl<K, V, M> VersionedMapStore<K, V> for SpillingInMemoryMap<K, V, M>
+where
+    K: Ord + Clone,
+    V: Clone,
+    M: VersionedMapStore<K, V>,
+{
+    fn get_at(&self, k: &K, height: u64) -> Option<V> {
+        self.map.get_at(k, height)
+    }
+
+    fn prune_before(&mut self, height: u64) {
+        self.map.prune_before(height);
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct InMemoryCell<T>
 where
@@ -112,6 +268,11 @@ where
         self.inner.get(idx).cloned()
     }
 
+    fn get_range(&self, start: usize, end: usize) -> Vec<T> {
+        let end = end.min(self.inner.len());
+        self.inner.get(start..end).map(|s| s.to_vec()).unwrap_or_default()
+    }
+
     fn append(&mut self, v: T) {
         self.inner.push(v);
     }
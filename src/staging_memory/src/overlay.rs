@@ -1,15 +1,27 @@
 use std::collections::BTreeMap;
 
-#[derive(Debug)]
-pub struct Overlay<K, V> {
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "candid",
+    derive(candid::CandidType, serde::Serialize, serde::Deserialize)
+)]
+pub struct Overlay<K: Ord, V> {
     pub staged: BTreeMap<K, Option<V>>,
+    /// Scratch id once the layer has been moved out of `staged` into the base's scratch store.
+    pub spilled: Option<u64>,
 }
 
 impl<K: Ord, V> Overlay<K, V> {
     pub fn new() -> Self {
         Self {
             staged: BTreeMap::new(),
+            spilled: None,
         }
     }
 }
 
+impl<K: Ord, V> Default for Overlay<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::traits::Layered;
+
+/// A writer tried to commit on top of a version that is no longer current.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VersionConflict {
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for VersionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "version conflict: expected {}, store is at {}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for VersionConflict {}
+
+#[derive(Debug)]
+struct Versioned<S> {
+    version: u64,
+    state: S,
+}
+
+/// A layered store shared between threads.
+///
+/// Readers take a `Snapshot` of the committed state at one version. Writers open a
+/// `WriteTxn`, which stages everything in a fresh layer and only bumps the version on
+/// commit; readers are excluded while a writer is open, so they never observe a
+/// partially applied transaction. Do slow work (I/O, decoding) before `begin`.
+#[derive(Debug)]
+pub struct Shared<S> {
+    inner: Arc<RwLock<Versioned<S>>>,
+}
+
+impl<S> Clone for Shared<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<S: Layered> Shared<S> {
+    pub fn new(state: S) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(Versioned { version: 0, state })),
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.read().version
+    }
+
+    pub fn snapshot(&self) -> Snapshot<'_, S> {
+        Snapshot { guard: self.read() }
+    }
+
+    /// Opens a write transaction, failing if another writer committed since `expected`.
+    pub fn begin(&self, expected: u64) -> Result<WriteTxn<'_, S>, VersionConflict> {
+        let guard = self.write();
+        if guard.version != expected {
+            return Err(VersionConflict {
+                expected,
+                actual: guard.version,
+            });
+        }
+        Ok(WriteTxn::open(guard))
+    }
+
+    /// Opens a write transaction on whatever version is current.
+    pub fn begin_latest(&self) -> WriteTxn<'_, S> {
+        WriteTxn::open(self.write())
+    }
+
+    /// Runs `f` on the committed state outside any transaction, for work that changes
+    /// where it is stored but not what readers see, such as writing committed layers
+    /// through to the base. Does not bump the version.
+    pub fn maintain<R>(&self, f: impl FnOnce(&mut S) -> R) -> R {
+        f(&mut self.write().state)
+    }
+
+    // A writer that panicked has already reverted its layer in `WriteTxn::drop`,
+    // so the state behind a poisoned lock is still the last committed one.
+    fn read(&self) -> RwLockReadGuard<'_, Versioned<S>> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Versioned<S>> {
+        self.inner.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub struct Snapshot<'a, S> {
+    guard: RwLockReadGuard<'a, Versioned<S>>,
+}
+
+impl<S> Snapshot<'_, S> {
+    pub fn version(&self) -> u64 {
+        self.guard.version
+    }
+}
+
+impl<S> Deref for Snapshot<'_, S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.guard.state
+    }
+}
+
+/// Exclusive access to the shared state with its own staged layer.
+/// Dropping it without `commit` reverts everything staged through it.
+pub struct WriteTxn<'a, S: Layered> {
+    guard: RwLockWriteGuard<'a, Versioned<S>>,
+    done: bool,
+}
+
+impl<'a, S: Layered> WriteTxn<'a, S> {
+    fn open(mut guard: RwLockWriteGuard<'a, Versioned<S>>) -> Self {
+        guard.state.push_layer();
+        Self { guard, done: false }
+    }
+
+    /// Version this transaction started from.
+    pub fn version(&self) -> u64 {
+        self.guard.version
+    }
+
+    /// Commits the transaction's layer and returns the new version.
+    pub fn commit(mut self) -> u64 {
+        self.guard.state.commit_top();
+        self.guard.version += 1;
+        self.done = true;
+        self.guard.version
+    }
+
+    pub fn abort(self) {}
+}
+
+impl<S: Layered> Deref for WriteTxn<'_, S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.guard.state
+    }
+}
+
+impl<S: Layered> DerefMut for WriteTxn<'_, S> {
+    fn deref_mut(&mut self) -> &mut S {
+        &mut self.guard.state
+    }
+}
+
+impl<S: Layered> Drop for WriteTxn<'_, S> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.guard.state.revert_top();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::LogTxn;
+    use crate::mem::InMemoryLog;
+
+    type Log = LogTxn<u32, InMemoryLog<u32>>;
+
+    fn shared() -> Shared<Log> {
+        Shared::new(LogTxn::new(InMemoryLog::new()))
+    }
+
+    #[test]
+    fn commit_bumps_version_and_is_visible() {
+        let shared = shared();
+        let mut txn = shared.begin(0).unwrap();
+        txn.append(1);
+        assert_eq!(txn.commit(), 1);
+        let snap = shared.snapshot();
+        assert_eq!(snap.version(), 1);
+        assert_eq!(snap.get(0), Some(1));
+    }
+
+    #[test]
+    fn stale_writer_is_refused() {
+        let shared = shared();
+        shared.begin_latest().commit();
+        let conflict = shared.begin(0).err().unwrap();
+        assert_eq!(conflict, VersionConflict { expected: 0, actual: 1 });
+        assert!(shared.begin(1).is_ok());
+    }
+
+    #[test]
+    fn dropped_txn_reverts() {
+        let shared = shared();
+        let mut txn = shared.begin(0).unwrap();
+        txn.append(1);
+        drop(txn);
+        assert_eq!(shared.version(), 0);
+        assert!(shared.snapshot().is_empty());
+    }
+
+    #[test]
+    fn maintain_keeps_the_version() {
+        let shared = shared();
+        let mut txn = shared.begin(0).unwrap();
+        txn.append(1);
+        txn.commit();
+        shared.maintain(|log| log.commit_all());
+        assert_eq!(shared.version(), 1);
+        assert_eq!(shared.snapshot().layers(), &[Vec::<u32>::new()]);
+        assert_eq!(shared.snapshot().get(0), Some(1));
+    }
+
+    #[test]
+    fn readers_never_see_half_a_txn() {
+        let shared = shared();
+        let writer = {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                for i in 0..200 {
+                    let mut txn = shared.begin_latest();
+                    txn.append(i);
+                    txn.append(i);
+                    txn.commit();
+                }
+            })
+        };
+        while !writer.is_finished() {
+            let snap = shared.snapshot();
+            assert_eq!(snap.len() % 2, 0);
+            assert_eq!(snap.len() as u64, snap.version() * 2);
+        }
+        writer.join().unwrap();
+        assert_eq!(shared.version(), 200);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::traits::CellStore;
+use crate::traits::{CellStore, Layered};
 
 #[derive(Debug)]
 pub struct StructTxn<T: Clone, B: CellStore<T>> {
@@ -69,10 +69,8 @@ impl<T: Clone, B: CellStore<T>> StructTxn<T, B> {
     }
 
     pub fn get(&self) -> Option<T> {
-        for layer in self.overlays.iter().rev() {
-            if let Some(v) = layer {
-                return Some(v.clone());
-            }
+        if let Some(v) = self.overlays.iter().rev().flatten().next() {
+            return Some(v.clone());
         }
         self.base.get()
     }
@@ -82,4 +80,29 @@ impl<T: Clone, B: CellStore<T>> StructTxn<T, B> {
         self.overlays.clear();
         self.overlays.push(None);
     }
+
+    pub fn layers(&self) -> &[Option<T>] {
+        &self.overlays
+    }
+
+    pub fn restore_layers(&mut self, layers: Vec<Option<T>>) {
+        self.overlays = layers;
+        if self.overlays.is_empty() {
+            self.overlays.push(None);
+        }
+    }
+}
+
+impl<T: Clone, B: CellStore<T>> Layered for StructTxn<T, B> {
+    fn push_layer(&mut self) {
+        StructTxn::push_layer(self)
+    }
+
+    fn commit_top(&mut self) {
+        StructTxn::commit_top(self)
+    }
+
+    fn revert_top(&mut self) {
+        StructTxn::revert_top(self)
+    }
 }
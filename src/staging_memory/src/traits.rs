@@ -13,6 +13,39 @@ where
             self.remove(k);
         }
     }
+    /// Called before a layer tagged with `height` is committed. Plain maps ignore it.
+    fn set_version(&mut self, _height: u64) {}
+    /// Storage for staged layers that grow too large for the heap, if the backend has any.
+    fn scratch(&self) -> Option<&dyn ScratchStore<K, V>> {
+        None
+    }
+    fn scratch_mut(&mut self) -> Option<&mut dyn ScratchStore<K, V>> {
+        None
+    }
+}
+
+/// Out-of-heap storage a `BTreeTxn` moves a staged layer into once it passes `threshold`
+/// entries. Entries are grouped by the scratch id the txn assigns to the spilled layer;
+/// `None` values are staged removals.
+pub trait ScratchStore<K, V> {
+    fn threshold(&self) -> usize;
+    fn get(&self, layer: u64, k: &K) -> Option<Option<V>>;
+    fn insert(&mut self, layer: u64, k: K, v: Option<V>);
+    /// Up to `limit` entries of `layer` in key order, starting after `after`.
+    fn entries(&self, layer: u64, after: Option<&K>, limit: usize) -> Vec<(K, Option<V>)>;
+    fn clear(&mut self, layer: u64);
+}
+
+/// A map that remembers every committed value by the version it was written at.
+pub trait VersionedMapStore<K, V>: MapStore<K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    /// Value of `k` as of `height`, i.e. the last write at or below it.
+    fn get_at(&self, k: &K, height: u64) -> Option<V>;
+    /// Drops history that is no longer needed to answer `get_at` for heights >= `height`.
+    fn prune_before(&mut self, height: u64);
 }
 
 pub trait CellStore<T>
@@ -29,8 +62,22 @@ where
     T: Clone,
 {
     fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     fn get(&self, idx: usize) -> Option<T>;
+    /// Items in `start..end`, clamped to the log length.
+    fn get_range(&self, start: usize, end: usize) -> Vec<T> {
+        (start..end.min(self.len())).filter_map(|i| self.get(i)).collect()
+    }
     fn append(&mut self, v: T);
     fn extend<I: IntoIterator<Item = T>>(&mut self, it: I);
     fn clear(&mut self);
 }
+
+/// Anything with a stack of staged layers.
+pub trait Layered {
+    fn push_layer(&mut self);
+    fn commit_top(&mut self);
+    fn revert_top(&mut self);
+}
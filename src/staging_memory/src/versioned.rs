@@ -0,0 +1,290 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::traits::{MapStore, VersionedMapStore};
+
+/// How much history a versioned map keeps when a new version starts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PrunePolicy {
+    #[default]
+    KeepAll,
+    /// Keep enough history to answer `get_at` for the last `n` versions.
+    KeepLast(u64),
+}
+
+impl PrunePolicy {
+    /// Height below which history may be dropped once `version` is current.
+    pub fn cutoff(&self, version: u64) -> Option<u64> {
+        match self {
+            PrunePolicy::KeepAll => None,
+            PrunePolicy::KeepLast(n) => version.checked_sub(*n),
+        }
+    }
+}
+
+/// Applies a `PrunePolicy` a little at a time: remembers which keys gained history at
+/// which version, so a new version only revisits the keys that fell below the cutoff
+/// instead of rescanning all history.
+#[derive(Debug, Default)]
+pub struct Pruner<K> {
+    policy: PrunePolicy,
+    written: BTreeMap<u64, BTreeSet<K>>,
+}
+
+impl<K: Ord + Clone> Pruner<K> {
+    pub fn new(policy: PrunePolicy) -> Self {
+        Self {
+            policy,
+            written: BTreeMap::new(),
+        }
+    }
+
+    /// Notes that `k` gained history at `version`; nothing to note if all of it is kept.
+    pub fn record(&mut self, version: u64, k: &K) {
+        if self.policy != PrunePolicy::KeepAll {
+            self.written.entry(version).or_default().insert(k.clone());
+        }
+    }
+
+    /// Once `version` is current: the cutoff, and the keys written below it since the
+    /// last call. Pruning each of those keys below the cutoff prunes the whole map.
+    pub fn due(&mut self, version: u64) -> Option<(u64, BTreeSet<K>)> {
+        let cutoff = self.policy.cutoff(version)?;
+        Some((cutoff, self.take_before(cutoff)))
+    }
+
+    /// Forgets the keys written below `height`, once their history was pruned otherwise.
+    pub fn forget_before(&mut self, height: u64) {
+        self.take_before(height);
+    }
+
+    pub fn clear(&mut self) {
+        self.written.clear();
+    }
+
+    fn take_before(&mut self, height: u64) -> BTreeSet<K> {
+        let rest = self.written.split_off(&height);
+        std::mem::replace(&mut self.written, rest)
+            .into_values()
+            .flatten()
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VersionedInMemoryMap<K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    current: BTreeMap<K, V>,
+    history: BTreeMap<(K, u64), Option<V>>,
+    version: u64,
+    pruner: Pruner<K>,
+}
+
+impl<K, V> VersionedInMemoryMap<K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn new() -> Self {
+        Self::with_policy(PrunePolicy::KeepAll)
+    }
+
+    pub fn with_policy(policy: PrunePolicy) -> Self {
+        Self {
+            current: BTreeMap::new(),
+            history: BTreeMap::new(),
+            version: 0,
+            pruner: Pruner::new(policy),
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    // What `prune_before(height)` drops, for `k` alone.
+    fn prune_key_before(&mut self, k: &K, height: u64) {
+        let below: Vec<(u64, bool)> = self
+            .history
+            .range((k.clone(), 0)..(k.clone(), height))
+            .map(|((_, h), v)| (*h, v.is_none()))
+            .collect();
+        let Some(&(newest, tombstone)) = below.last() else {
+            return;
+        };
+        for (h, _) in below {
+            if h != newest || tombstone {
+                self.history.remove(&(k.clone(), h));
+            }
+        }
+    }
+}
+
+impl<K, V> MapStore<K, V> for VersionedInMemoryMap<K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    fn get(&self, k: &K) -> Option<V> {
+        self.current.get(k).cloned()
+    }
+
+    fn put(&mut self, k: K, v: V) {
+        self.pruner.record(self.version, &k);
+        self.history.insert((k.clone(), self.version), Some(v.clone()));
+        self.current.insert(k, v);
+    }
+
+    fn remove(&mut self, k: &K) {
+        self.pruner.record(self.version, k);
+        self.history.insert((k.clone(), self.version), None);
+        self.current.remove(k);
+    }
+
+    fn keys(&self) -> Vec<K> {
+        self.current.keys().cloned().collect()
+    }
+
+    fn clear(&mut self) {
+        self.current.clear();
+        self.history.clear();
+        self.pruner.clear();
+        self.version = 0;
+    }
+
+    fn set_version(&mut self, height: u64) {
+        self.version = height;
+        if let Some((cutoff, keys)) = self.pruner.due(height) {
+            for k in keys {
+                self.prune_key_before(&k, cutoff);
+            }
+        }
+    }
+}
+
+impl<K, V> VersionedMapStore<K, V> for VersionedInMemoryMap<K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    fn get_at(&self, k: &K, height: u64) -> Option<V> {
+        self.history
+            .range((k.clone(), 0)..=(k.clone(), height))
+            .next_back()
+            .and_then(|(_, v)| v.clone())
+    }
+
+    fn prune_before(&mut self, height: u64) {
+        // Per key, only the newest entry below `height` is still needed,
+        // and not even that one if it is a tombstone.
+        let mut drop: Vec<(K, u64)> = Vec::new();
+        let mut last: Option<(K, u64, bool)> = None;
+        for ((k, h), v) in self.history.iter() {
+            if *h >= height {
+                continue;
+            }
+            if let Some((pk, ph, tombstone)) = last.take() {
+                if pk == *k || tombstone {
+                    drop.push((pk, ph));
+                }
+            }
+            last = Some((k.clone(), *h, v.is_none()));
+        }
+        if let Some((pk, ph, true)) = last {
+            drop.push((pk, ph));
+        }
+        for key in drop {
+            self.history.remove(&key);
+        }
+        self.pruner.forget_before(height);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map_at(
+        writes: &[(u64, &'static str, Option<u32>)],
+    ) -> VersionedInMemoryMap<&'static str, u32> {
+        let mut m = VersionedInMemoryMap::new();
+        for &(height, k, v) in writes {
+            m.set_version(height);
+            match v {
+                Some(v) => m.put(k, v),
+                None => m.remove(&k),
+            }
+        }
+        m
+    }
+
+    #[test]
+    fn prune_keeps_newest_value_below_cutoff() {
+        let mut m = map_at(&[(1, "a", Some(1)), (2, "a", Some(2)), (5, "a", Some(5))]);
+        m.prune_before(4);
+        assert_eq!(m.history.len(), 2);
+        assert_eq!(m.get_at(&"a", 4), Some(2));
+        assert_eq!(m.get_at(&"a", 5), Some(5));
+    }
+
+    #[test]
+    fn prune_drops_tombstone_and_what_it_removed() {
+        let mut m = map_at(&[(1, "a", Some(1)), (2, "a", None), (1, "b", Some(7))]);
+        m.prune_before(3);
+        assert_eq!(m.history.keys().collect::<Vec<_>>(), vec![&("b", 1)]);
+        assert_eq!(m.get_at(&"a", 3), None);
+        assert_eq!(m.get_at(&"b", 3), Some(7));
+    }
+
+    #[test]
+    fn prune_drops_tombstone_on_last_key() {
+        let mut m = map_at(&[(1, "a", Some(1)), (1, "b", Some(2)), (2, "b", None)]);
+        m.prune_before(3);
+        assert_eq!(m.history.keys().collect::<Vec<_>>(), vec![&("a", 1)]);
+        assert_eq!(m.get_at(&"b", 3), None);
+    }
+
+    #[test]
+    fn prune_leaves_tombstones_at_or_above_cutoff() {
+        let mut m = map_at(&[(1, "a", Some(1)), (3, "a", None)]);
+        m.prune_before(3);
+        assert_eq!(m.get_at(&"a", 2), Some(1));
+        assert_eq!(m.get_at(&"a", 3), None);
+        assert_eq!(m.history.len(), 2);
+    }
+
+    #[test]
+    fn keep_last_prunes_on_new_version() {
+        let mut m = VersionedInMemoryMap::with_policy(PrunePolicy::KeepLast(2));
+        m.set_version(1);
+        m.put("a", 1);
+        m.set_version(2);
+        m.put("a", 2);
+        m.set_version(5);
+        assert_eq!(m.history.len(), 1);
+        assert_eq!(m.get_at(&"a", 3), Some(2));
+    }
+
+    #[test]
+    fn keep_last_prunes_like_a_full_prune() {
+        let mut kept = VersionedInMemoryMap::with_policy(PrunePolicy::KeepLast(3));
+        let mut full = VersionedInMemoryMap::new();
+        for height in 1..=20u64 {
+            for m in [&mut kept, &mut full] {
+                m.set_version(height);
+                let k = ["a", "b", "c"][height as usize % 3];
+                if height % 4 == 0 {
+                    m.remove(&k);
+                } else {
+                    m.put(k, height as u32);
+                }
+            }
+            full.prune_before(height.saturating_sub(3));
+            assert_eq!(kept.history, full.history, "at height {height}");
+        }
+        // Only keys written at or above the last cutoff, 17, are still waiting.
+        assert_eq!(kept.pruner.written.keys().collect::<Vec<_>>(), vec![&17, &18, &19, &20]);
+    }
+}